@@ -0,0 +1,9 @@
+use std::{env, process::Command};
+
+// The compiler version is part of the manifest, plugins built with a different compiler than the loader are not compatible
+fn main() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc).arg("--version").output().ok().and_then(|out| String::from_utf8(out.stdout).ok()).unwrap_or_default();
+    println!("cargo:rustc-env=SDK_RUSTC_VERSION={}", version.trim());
+    println!("cargo:rerun-if-env-changed=RUSTC");
+}
@@ -1,27 +1,90 @@
-use std::{num::{ParseIntError, ParseFloatError}, str::ParseBoolError, sync::{Arc, atomic::{AtomicBool, Ordering}}};
+// TODO: Implement some Error types, until then the blank Result::Err() is intended
+#![allow(clippy::result_unit_err)]
 
+use std::{num::{ParseIntError, ParseFloatError}, str::ParseBoolError, sync::{Arc, atomic::{AtomicBool, Ordering}}, fmt::Display, time::{Duration, SystemTime}};
+
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use tokio::sync::{mpsc, oneshot};
 
 pub mod depreciated;
 
 pub trait Datastore: Sync {
     /// This creates a key and sets the value to a certain type<br>
-    /// If the key already exist this function return Err<br>
+    /// If the key already exist (or the access token is not from a registered plugin) this function return Err<br>
     /// Any type set in this inilial setting will be type future submissions will be coerced into
     fn create_value(&self, key: String, access_token: &AccessToken, val_type: Value) -> Result<DataHandle,()>; // TODO: Implement some Error types
 
+    /// Same as create_value, but with additional metadata describing the value<br>
+    /// The range in the metadata is applied to the initial value too, so this Errs if it is rejected
+    fn create_value_with_metadata(&self, key: String, access_token: &AccessToken, val_type: Value, metadata: Metadata) -> Result<DataHandle,()>;
+
+    /// Creates a value that is computed from other values, and recomputed whenever one of them changes<br>
+    /// The expression can reference keys directly (`Speed * 3.6`), or in braces (`{Lap Time} / 60`),<br>
+    /// and supports arithmetic, comparisons, `&& || !` and the functions abs, min, max and if(cond, a, b).<br>
+    /// All keys have to exist already. The type of the value is the type the expression evaluates to initially.<br>
+    /// Renamed inputs are still followed, if an input is deleted the derived value is deleted too.<br>
+    /// Derived values can not be set, not even by their owner
+    fn create_derived_value(&self, key: String, access_token: &AccessToken, expression: &str, metadata: Metadata) -> Result<DataHandle,()>;
+
+    /// Returns the metadata of the value for a handle
+    fn get_metadata(&self, handle: &DataHandle) -> Result<Metadata, ()>;
+
     /// Can be called on a value for a handle <br>
     /// Using a type that is not the same as the inital will result into it being converted,<br>
-    /// which may Err and be returned by this function
+    /// which may Err and be returned by this function<br>
+    /// Also Errs if the value is read only and the loader finished init, or it is out of range with RangePolicy::Reject
     fn set_value(&self, handle: &DataHandle, access_token: &AccessToken, val: Value) -> Result<(),()>;
 
     /// Returns the Value for a handle<br>
     /// Will fail if the data has been renamed or removed
     fn get_value(&self, handle: &DataHandle) -> Result<Value, ()>;
 
+    /// Returns the recorded history of a value, oldest first<br>
+    /// Errs if the value keeps no history (see Metadata::history) or the handle is outdated
+    fn get_history(&self, handle: &DataHandle, range: HistoryRange) -> Result<Vec<HistorySample>, ()>;
+
+    /// Sets multiple values at once, all of which have to be owned by this access token<br>
+    /// Readers using get_values will either see all or none of these changes<br>
+    /// If any handle is outdated, not owned, or the value can not be converted nothing is set and Err is returned
+    fn set_values(&self, access_token: &AccessToken, values: Vec<(&DataHandle, Value)>) -> Result<(), ()>;
+
+    /// Returns the values for multiple handles, in the same order<br>
+    /// This is a consistent snapshot with regards to set_values, so you never observe half of a batch<br>
+    /// Fails if any of the handles has been renamed or removed
+    fn get_values(&self, handles: &[DataHandle]) -> Result<Vec<Value>, ()>;
+
+    /// Atomically replaces the value with new, but only if it is currently equal to current<br>
+    /// Both values are converted into the type of the value first (Float is compared bit by bit)<br>
+    /// Returns Ok(true) if the value was swapped, Ok(false) if it didn't match
+    fn compare_and_swap(&self, handle: &DataHandle, access_token: &AccessToken, current: Value, new: Value) -> Result<bool, ()>;
+
+    /// Atomically adds to an Int or Float value, returning the previous value<br>
+    /// Any other type will Err
+    fn fetch_add(&self, handle: &DataHandle, access_token: &AccessToken, val: Value) -> Result<Value, ()>;
+
+    /// Atomically flips a Bool value, returning the previous value<br>
+    /// Any other type will Err
+    fn toggle(&self, handle: &DataHandle, access_token: &AccessToken) -> Result<bool, ()>;
+
+    /// Atomically appends to a Str value<br>
+    /// Any other type will Err
+    fn append(&self, handle: &DataHandle, access_token: &AccessToken, text: &str) -> Result<(), ()>;
+
+    /// Removes the value, only the owner can do this<br>
+    /// All handles to it become outdated, and the key can be created again
+    fn delete_value(&self, handle: &DataHandle, access_token: &AccessToken) -> Result<(), ()>;
+
+    /// Renames the value to new_key, only the owner can do this<br>
+    /// Fails if new_key already exists. Returns the new handle, all previous handles become outdated
+    fn rename_value(&self, handle: &DataHandle, access_token: &AccessToken, new_key: String) -> Result<DataHandle, ()>;
+
     /// Returns you the datahandle for a given key, or Err if it doesn't exist
     fn get_data_handle(&self, key: &str) -> Option<DataHandle>;
 
+    /// Lists all keys matching the pattern, sorted by key<br>
+    /// Allows you to discover what exists without knowing the exact key
+    fn list_keys(&self, pattern: &KeyPattern) -> Vec<KeyInfo>;
+
     /// To be called in Init<br>
     /// Registers a plugin, returning the plugins access token on success
     fn register_plugin(&self, plugin: Plugin) -> Option<AccessToken>;
@@ -31,15 +94,68 @@ pub trait Datastore: Sync {
     fn deregister_plugin(&self, access_token: &AccessToken) -> bool;
 
     /// Returns the handle to a plugin, allowing you to contact it internally
+    #[allow(clippy::ptr_arg)]
     fn get_plugin(&self, name: &String) -> Option<Plugin>;
 
-    
+    /// Returns the `[plugin.<name>]` section of the loader config, where name is the file name without `lib` and the extension<br>
+    /// (so `env!("CARGO_CRATE_NAME")` of the plugin). Empty if there is no such section<br>
+    /// If the plugin exports `config_changed` it is called with the new section whenever it is reloaded
+    fn get_plugin_config(&self, name: &str) -> PluginConfig;
+
+    /// Logs through the logger of the loader, tagged with the name and version of the plugin the token belongs to<br>
+    /// Easier to use via `Logger`
+    fn log(&self, access_token: &AccessToken, record: LogRecord);
+
+    /// Returns if records of this level are logged for the plugin, so building expensive ones can be skipped
+    fn log_enabled(&self, access_token: &AccessToken, level: LogLevel) -> bool;
+
+    /// Returns what the plugin did so far, None if it never did anything<br>
+    /// Name is the file name without `lib` and the extension like for get_plugin_config (remote plugins use their registered name)
+    fn get_plugin_stats(&self, name: &str) -> Option<PluginStats>;
 }
 
 pub enum ManagerError {
     
 }
 
+/// Function a plugin can provide to be called directly by other plugins
+pub type InteractFunc = fn(methode: String, args: String) -> Result<String, ()>;
+
+/// Message passed over the channel of a plugin: methode, args and the callback for the response
+pub type PluginMessage = (String, String, oneshot::Sender<Result<String, ()>>);
+
+/// Runtime statistics of a plugin, as collected by the loader<br>
+/// Calls are counted for the plugin whose init, update, end or interact runs on the thread,<br>
+/// on threads the plugin spawned itself only calls with an access token are counted
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PluginStats {
+    pub init_time: Duration,
+    /// Time spent in all update calls together
+    pub update_time: Duration,
+    pub update_calls: u64,
+    pub end_time: Duration,
+    /// Reads of values
+    pub gets: u64,
+    /// Writes of values, set_values counts once
+    pub sets: u64,
+    pub creates: u64,
+    /// Interact and send calls other plugins made to this one
+    pub interacts_served: u64,
+    /// Interact and send calls this plugin made to others
+    pub interacts_made: u64,
+    /// Failed Datastore calls, interact calls answered with an error, failed init and panics in update
+    pub errors: u64,
+    pub last_error: Option<String>,
+    /// Why the plugin is unhealthy, like a call that missed its deadline. It is not called anymore
+    pub unhealthy: Option<String>
+}
+
+/// Wraps calls between plugins, handed out by the loader with the Plugin so it can trace them<br>
+/// kind is interact or send, call does the actual call to the plugin (and can be moved to another thread, to enforce a deadline)
+pub trait CallTracer: Send + Sync + std::fmt::Debug {
+    fn trace(&self, kind: &'static str, plugin: &str, methode: &str, call: Box<dyn FnOnce() -> Result<String, ()> + Send>) -> Result<String, ()>;
+}
+
 #[derive(Debug, Clone)]
 pub struct Plugin {
     // Something I realiced: What if a plugin deregisters, but another plugin had stored the handle from the previous callback...
@@ -47,30 +163,53 @@ pub struct Plugin {
     // So we have this Arc to allow all Copies to stay up to date and to no longer call the plugin if gone
     offline: Arc<AtomicBool>,
     
-    run: Option<fn(methode: String, args: String) -> Result<String, ()>>,
-    sender: Option<mpsc::Sender<(String,String,oneshot::Sender<Result<String, ()>>)>>,
+    run: Option<InteractFunc>,
+    sender: Option<mpsc::Sender<PluginMessage>>,
+    tracer: Option<Arc<dyn CallTracer>>,
     pub version: String,
     pub name: String
 }
 
 impl Plugin {
-    pub fn new(name: String, version: String, interact_func: Option<fn(methode: String, args: String) -> Result<String, ()>>) -> Self {
-        Plugin { run: interact_func, version, name, offline: Arc::new(AtomicBool::new(false)), sender: None }
+    pub fn new(name: String, version: String, interact_func: Option<InteractFunc>) -> Self {
+        Plugin { run: interact_func, version, name, offline: Arc::new(AtomicBool::new(false)), sender: None, tracer: None }
+    }
+
+    /// Plugin that is interacted with over the channel, each message carries the callback for the answer<br>
+    /// The loader uses this for remote plugins, that live in another process
+    pub fn new_with_channel(name: String, version: String, sender: mpsc::Sender<PluginMessage>) -> Self {
+        Plugin { run: None, version, name, offline: Arc::new(AtomicBool::new(false)), sender: Some(sender), tracer: None }
     }
 
     /// Used by the DataStore to hand out new clones, but with the switch offhandle it stores internally
     pub fn renew(&self, switch_offhandle: Arc<AtomicBool>) -> Self {
-        Plugin { offline: switch_offhandle, run: self.run.clone(), version: self.version.clone(), name: self.name.clone(), sender: self.sender.clone() }
+        Plugin { offline: switch_offhandle, run: self.run, version: self.version.clone(), name: self.name.clone(), sender: self.sender.clone(), tracer: self.tracer.clone() }
+    }
+
+    /// Used by the DataStore, so calls to this plugin are traced
+    pub fn with_tracer(mut self, tracer: Arc<dyn CallTracer>) -> Self {
+        self.tracer = Some(tracer);
+        self
     }
 
     pub fn interact(&self, methode: String, args: String) -> Result<String, ()> {
+        match &self.tracer {
+            Some(tracer) => {
+                let plugin = self.clone();
+                tracer.trace("interact", &self.name, &methode.clone(), Box::new(move || plugin.call(methode, args)))
+            },
+            None => self.call(methode, args)
+        }
+    }
+
+    fn call(&self, methode: String, args: String) -> Result<String, ()> {
         if self.offline.load(Ordering::Acquire) {
             return Err(());
         } else if let Some(met) = self.run {
             return met(methode, args);
         } else if let Some(sender) = &self.sender {
             let (sx, rx) = oneshot::channel::<Result<String,()>>();
-            if let Err(_) = sender.blocking_send((methode, args, sx)) {
+            if sender.blocking_send((methode, args, sx)).is_err() {
                 return Err(());
             }
             if let Ok(val) = rx.blocking_recv() {
@@ -88,15 +227,22 @@ impl Plugin {
         if !self.is_online() {
             return Err(());
         } else if let Some(sender) = &self.sender {
-            if let Err(_) = sender.send((methode, args, callback)).await {
+            // The answer arrives later on the callback, so only that it was sent is traced
+            if let Some(tracer) = &self.tracer {
+                let _ = tracer.trace("send", &self.name, &methode, Box::new(|| Ok(String::new())));
+            }
+            if sender.send((methode, args, callback)).await.is_err() {
                 return Err(());
             } else {
                 return Ok(());
             }
         } else if let Some(met) = self.run {
-            let res = met(methode, args);
+            let res = match &self.tracer {
+                Some(tracer) => tracer.trace("send", &self.name, &methode.clone(), Box::new(move || met(methode, args))),
+                None => met(methode, args)
+            };
 
-            if let Err(_) = callback.send(res) {
+            if callback.send(res).is_err() {
                 return Err(());
             } else {
                 return Ok(());
@@ -119,7 +265,169 @@ impl Plugin {
     }
 }
 
-#[derive(Debug, Clone,PartialEq)]
+/// Version of the sdk, plugins have to be built against the same one as the loader
+pub const SDK_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Compiler the sdk was built with, as the Datastore is passed as a rust trait object this has to match too
+pub const RUSTC_VERSION: &str = env!("SDK_RUSTC_VERSION");
+
+/// Describes a plugin, so the loader can inspect it without initializing it<br>
+/// Exported via `export_manifest!()`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    pub description: Option<String>,
+    pub sdk_version: String,
+    pub rustc_version: String
+}
+
+impl Manifest {
+    pub fn new(name: &str, version: &str, description: Option<&str>) -> Self {
+        Manifest {
+            name: name.to_string(),
+            version: version.to_string(),
+            description: description.filter(|d| !d.is_empty()).map(str::to_string),
+            sdk_version: SDK_VERSION.to_string(),
+            rustc_version: RUSTC_VERSION.to_string()
+        }
+    }
+}
+
+/// Exports the manifest of your plugin, filled from your Cargo.toml<br>
+/// The manifest is handed out as a C string of toml, so it can be read from any plugin, even an incompatible one
+#[macro_export]
+macro_rules! export_manifest {
+    () => {
+        #[no_mangle]
+        pub extern "C" fn plugin_manifest() -> *const ::std::os::raw::c_char {
+            static MANIFEST: ::std::sync::OnceLock<::std::ffi::CString> = ::std::sync::OnceLock::new();
+            MANIFEST.get_or_init(|| {
+                let manifest = $crate::Manifest::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), option_env!("CARGO_PKG_DESCRIPTION"));
+                ::std::ffi::CString::new($crate::manifest_to_string(&manifest)).unwrap_or_default()
+            }).as_ptr()
+        }
+    };
+}
+
+#[doc(hidden)]
+pub fn manifest_to_string(manifest: &Manifest) -> String {
+    toml::to_string(manifest).unwrap_or_default()
+}
+
+/// Config section of a single plugin, as written in the loader config
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PluginConfig {
+    table: toml::Table
+}
+
+impl PluginConfig {
+    pub fn new(table: toml::Table) -> Self {
+        PluginConfig { table }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    /// The section as it was written
+    pub fn table(&self) -> &toml::Table {
+        &self.table
+    }
+
+    /// Deserializes the section into your config type<br>
+    /// Use `#[serde(default)]` on it, so leaving out fields (or the whole section) falls back to your defaults
+    pub fn get<T: DeserializeOwned>(&self) -> Result<T, String> {
+        self.table.clone().try_into().map_err(|e: toml::de::Error| e.message().to_string())
+    }
+}
+
+/// Severity of a log record, Error being the most severe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogLevel::Error => write!(f, "error"),
+            LogLevel::Warn => write!(f, "warn"),
+            LogLevel::Info => write!(f, "info"),
+            LogLevel::Debug => write!(f, "debug"),
+            LogLevel::Trace => write!(f, "trace"),
+        }
+    }
+}
+
+/// A log message with structured fields, written as `message key=value` by the loader
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    pub message: String,
+    pub fields: Vec<(String, Value)>
+}
+
+/// Logs through the loader, which tags every record with the name and version of your plugin<br>
+/// Create it in init after registering with `Logger::new(storage, token.clone())`, there is no global logger inside the plugin<br>
+/// Fields are passed along with the level: `logger.log(LogLevel::Debug, "Finish set", &[("value", Value::Float(5.24))])`
+#[derive(Clone)]
+pub struct Logger {
+    storage: &'static dyn Datastore,
+    access_token: AccessToken
+}
+
+impl Logger {
+    pub fn new(storage: &'static dyn Datastore, access_token: AccessToken) -> Self {
+        Logger { storage, access_token }
+    }
+
+    /// Returns if records of this level are logged, per plugin levels are set in the loader config
+    pub fn enabled(&self, level: LogLevel) -> bool {
+        self.storage.log_enabled(&self.access_token, level)
+    }
+
+    pub fn log(&self, level: LogLevel, message: impl Into<String>, fields: &[(&str, Value)]) {
+        if !self.enabled(level) {
+            return;
+        }
+        let fields = fields.iter().map(|(key, value)| (key.to_string(), value.clone())).collect();
+        self.storage.log(&self.access_token, LogRecord { level, message: message.into(), fields });
+    }
+
+    pub fn error(&self, message: impl Into<String>) {
+        self.log(LogLevel::Error, message, &[]);
+    }
+
+    pub fn warn(&self, message: impl Into<String>) {
+        self.log(LogLevel::Warn, message, &[]);
+    }
+
+    pub fn info(&self, message: impl Into<String>) {
+        self.log(LogLevel::Info, message, &[]);
+    }
+
+    pub fn debug(&self, message: impl Into<String>) {
+        self.log(LogLevel::Debug, message, &[]);
+    }
+
+    pub fn trace(&self, message: impl Into<String>) {
+        self.log(LogLevel::Trace, message, &[]);
+    }
+}
+
+impl std::fmt::Debug for Logger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Logger").field("access_token", &self.access_token).finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct AccessToken {
     token: String // Allows in the future to change this to something better (like a fixed size u8)
 }
@@ -137,15 +445,189 @@ pub struct DataHandle {
     pub name_hash: u64
 }
 
+/// Pattern to select keys with
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyPattern {
+    All,
+    /// Matches all keys starting with this
+    Prefix(String),
+    /// `*` matches any number of characters, `?` exactly one, everything else matches itself
+    Glob(String)
+}
+
+impl KeyPattern {
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            KeyPattern::All => true,
+            KeyPattern::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            KeyPattern::Glob(glob) => glob_matches(glob, key),
+        }
+    }
+}
+
+/// Iterative glob matching, on a mismatch we backtrack to the last `*` and let it consume one more character<br>
+/// Works on slices of both strings, so nothing is allocated
+fn glob_matches(glob: &str, key: &str) -> bool {
+    let (mut g, mut k) = (glob, key);
+    let mut star: Option<(&str, &str)> = None;
+
+    while let Some(c) = k.chars().next() {
+        match g.chars().next() {
+            Some(p) if p == '?' || p == c => {
+                g = &g[p.len_utf8()..];
+                k = &k[c.len_utf8()..];
+            },
+            Some('*') => {
+                g = &g[1..];
+                star = Some((g, k));
+            },
+            _ => match star {
+                Some((star_g, star_k)) => {
+                    // k is a non empty suffix of star_k, so there is always a character to skip
+                    let skipped = star_k.chars().next().map_or(0, char::len_utf8);
+                    g = star_g;
+                    k = &star_k[skipped..];
+                    star = Some((star_g, k));
+                },
+                None => return false
+            }
+        }
+    }
+
+    g.chars().all(|c| c == '*')
+}
+
+/// Information about a key, as returned by list_keys
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyInfo {
+    pub key: String,
+    /// Name of the plugin that created the value
+    pub owner: String,
+    pub value_type: ValueType,
+    pub handle: DataHandle
+}
+
+/// The type of a Value, without the data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValueType {
+    Int,
+    Float,
+    Bool,
+    Str
+}
+
+impl Display for ValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueType::Int => write!(f, "Int"),
+            ValueType::Float => write!(f, "Float"),
+            ValueType::Bool => write!(f, "Bool"),
+            ValueType::Str => write!(f, "Str"),
+        }
+    }
+}
+
+/// Optional information about a value, set on creation<br>
+/// Construct it with `Metadata { unit: Some("m".to_string()), ..Default::default() }`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    /// Human readable description
+    pub description: Option<String>,
+    /// Physical unit the value is in
+    pub unit: Option<String>,
+    /// Lower bound for Int and Float values
+    pub min: Option<f64>,
+    /// Upper bound for Int and Float values
+    pub max: Option<f64>,
+    /// What to do with values outside of min/max
+    pub range_policy: RangePolicy,
+    /// The value this should be considered to be when nothing was published yet
+    pub default: Option<Value>,
+    /// Number of decimals to display a Float with
+    pub precision: Option<usize>,
+    /// Once all plugins finished their init the value can no longer be set
+    pub read_only_after_init: bool,
+    /// Keep previous values, retrievable via get_history
+    pub history: Option<HistoryLimit>,
+    /// The loader saves the value to disk, and restores it when the value is created again after a restart
+    pub persist: bool
+}
+
+impl Metadata {
+    /// Formats the value with the precision and unit of this metadata
+    pub fn display(&self, val: &Value) -> String {
+        let val = match (val, self.precision) {
+            (Value::Float(f), Some(precision)) => format!("{:.*}", precision, f),
+            _ => val.to_string()
+        };
+
+        match &self.unit {
+            Some(unit) => format!("{} {}", val, unit),
+            None => val
+        }
+    }
+}
+
+/// How values outside of the range set in the Metadata are handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RangePolicy {
+    /// Setting the value Errs
+    #[default]
+    Reject,
+    /// The value is set to the closest bound
+    Clamp
+}
+
+/// How much history of a value is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HistoryLimit {
+    /// Keep the last n samples
+    Samples(usize),
+    /// Keep all samples younger than this
+    Duration(Duration)
+}
+
+/// Which part of the history to return
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryRange {
+    All,
+    /// The last n samples
+    Last(usize),
+    /// All samples recorded at or after this time
+    Since(SystemTime)
+}
+
+/// A value as it was set at a certain time
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistorySample {
+    pub time: SystemTime,
+    pub value: Value
+}
+
 /// Universal Value Type for Data contained in the Datastore<br>
 /// Can easily be converted into most types
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Value {
     Int(i64),
+    #[serde(with = "float_serde")]
     Float(f64),
     Bool(bool),
     Str(String)
 }
 
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::Int(_) => ValueType::Int,
+            Value::Float(_) => ValueType::Float,
+            Value::Bool(_) => ValueType::Bool,
+            Value::Str(_) => ValueType::Str,
+        }
+    }
+}
+
+#[allow(clippy::to_string_trait_impl)]
 impl ToString for Value {
     fn to_string(&self) -> String {
         match self {
@@ -196,9 +678,72 @@ impl TryInto<bool> for Value {
     }
 }
 
+#[allow(clippy::from_over_into)]
 impl Into<String> for Value {
 
     fn into(self) -> String {
         self.to_string()
     }
-}
\ No newline at end of file
+}
+
+/// JSON has no NaN or infinities, so those floats are written as strings ("NaN", "inf", "-inf")
+mod float_serde {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(float: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        if float.is_finite() {
+            serializer.serialize_f64(*float)
+        } else {
+            serializer.serialize_str(&float.to_string())
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Float {
+            Number(f64),
+            Str(String)
+        }
+
+        match Float::deserialize(deserializer)? {
+            Float::Number(float) => Ok(float),
+            Float::Str(str) => str.parse().map_err(D::Error::custom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_matches;
+
+    #[test]
+    fn glob_empty_pattern_only_matches_empty_key() {
+        assert!(glob_matches("", ""));
+        assert!(!glob_matches("", "a"));
+    }
+
+    #[test]
+    fn glob_star_matches_everything() {
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*", "sensor.temp"));
+        assert!(glob_matches("**", "äöü"));
+    }
+
+    #[test]
+    fn glob_several_stars() {
+        assert!(glob_matches("*.*.value", "room.sensor.value"));
+        assert!(glob_matches("a*b*c", "abc"));
+        assert!(glob_matches("a*b*c", "axxbyybzc"));
+        assert!(!glob_matches("a*b*c", "axxcyyb"));
+        assert!(glob_matches("*?ä*", "xä"));
+    }
+
+    #[test]
+    fn glob_trailing_literal() {
+        assert!(glob_matches("*.temp", "room.temp"));
+        assert!(glob_matches("*.temp", "a.temp.temp"));
+        assert!(!glob_matches("*.temp", "room.temp2"));
+        assert!(!glob_matches("room?", "room"));
+    }
+}
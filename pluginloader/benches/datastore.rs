@@ -0,0 +1,80 @@
+//! Measures the read latency of get_value while other threads are creating values<br>
+//! Run with `cargo bench -p pluginloader`
+
+use std::{sync::{Arc, Barrier, atomic::{AtomicBool, Ordering}}, thread, time::{Duration, Instant}};
+
+use plugin_sdk::{Datastore, AccessToken, Value, Plugin, DataHandle};
+use pluginloader::datastore::Data;
+
+const READERS: usize = 4;
+const READS_PER_READER: usize = 200_000;
+const PREFILLED: usize = 1_000;
+
+fn main() {
+    println!("Read latency without concurrent creation:");
+    run(0);
+    println!("Read latency with 1 creating thread:");
+    run(1);
+    println!("Read latency with 2 creating threads:");
+    run(2);
+}
+
+fn run(creators: usize) {
+    let data: &'static Data = Box::leak(Box::new(Data::new()));
+    let token = data.register_plugin(Plugin::new("bench".to_string(), "0.1.0".to_string(), None)).expect("Unable to register");
+
+    let handles: Arc<Vec<DataHandle>> = Arc::new((0..PREFILLED).map(|i| {
+        data.create_value(format!("prefilled{}", i), &token, Value::Int(i as i64)).expect("Unable to create value")
+    }).collect());
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let barrier = Arc::new(Barrier::new(READERS + creators));
+
+    let creator_threads: Vec<_> = (0..creators).map(|c| {
+        let (stop, barrier, token) = (stop.clone(), barrier.clone(), token.clone());
+        thread::spawn(move || create_until(data, token, c, stop, barrier))
+    }).collect();
+
+    let reader_threads: Vec<_> = (0..READERS).map(|r| {
+        let (handles, barrier) = (handles.clone(), barrier.clone());
+        thread::spawn(move || read_latencies(data, handles, r, barrier))
+    }).collect();
+
+    let mut latencies: Vec<Duration> = reader_threads.into_iter().flat_map(|t| t.join().expect("Reader died")).collect();
+    stop.store(true, Ordering::Release);
+    let created: usize = creator_threads.into_iter().map(|t| t.join().expect("Creator died")).sum();
+
+    latencies.sort_unstable();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize].as_nanos();
+    println!("    {} reads, {} values created: p50 {}ns, p99 {}ns, p99.9 {}ns, max {}ns",
+        latencies.len(), created, percentile(0.5), percentile(0.99), percentile(0.999), percentile(1.0));
+
+    data.deregister_plugin(&token);
+}
+
+fn create_until(data: &'static Data, token: AccessToken, creator: usize, stop: Arc<AtomicBool>, barrier: Arc<Barrier>) -> usize {
+    barrier.wait();
+
+    let mut created = 0;
+    while !stop.load(Ordering::Acquire) {
+        data.create_value(format!("created{}_{}", creator, created), &token, Value::Str("Hello World!".to_string())).expect("Unable to create value");
+        created += 1;
+    }
+    created
+}
+
+fn read_latencies(data: &'static Data, handles: Arc<Vec<DataHandle>>, reader: usize, barrier: Arc<Barrier>) -> Vec<Duration> {
+    barrier.wait();
+
+    let mut latencies = Vec::with_capacity(READS_PER_READER);
+    for i in 0..READS_PER_READER {
+        let handle = &handles[(i * 7 + reader) % handles.len()];
+
+        let start = Instant::now();
+        let val = data.get_value(handle);
+        latencies.push(start.elapsed());
+
+        assert!(val.is_ok());
+    }
+    latencies
+}
@@ -0,0 +1,172 @@
+//! Admin console to poke at the live store, read from stdin or a local unix socket<br>
+//! One command per line, `help` lists them. Values are set with the admin token, so every value can be changed.<br>
+//! While a console is open the loader keeps running until shutdown is entered (or stdin is closed).
+
+use std::{io::{self, BufRead, BufReader, Write}, path::Path, thread, time::{Duration, Instant}};
+
+use log::info;
+use plugin_sdk::{Datastore, KeyPattern, PluginStats, Value};
+
+use crate::{datastore::Data, host::Host, listener, shutdown::Shutdown};
+
+/// How often watch looks at the value
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const WATCH_DEFAULT_SECS: u64 = 10;
+
+const HELP: &str = "\
+plugins                         lists the registered plugins and the loaded libraries
+keys [glob]                     lists the keys, all or those matching the glob (* and ?)
+get <key>                       prints the value
+set <key> <value>               sets the value, converted into the type of the key
+interact <plugin> <methode> [args]
+                                calls interact on the plugin and prints the answer
+unload <library>                ends the plugin and deletes its values
+reload <library>                unloads the plugin and loads it again from its file
+stats [plugin]                  prints the runtime statistics of all plugins, or of one by its library name
+watch <key> [seconds]           prints the value whenever it changes, for 10 seconds by default
+shutdown                        ends all plugins and shuts the loader down
+help                            prints this
+quit                            closes the console";
+
+/// Reads commands from stdin, until it is closed or quit is entered<br>
+/// Closing it shuts the loader down, as it could not be told to do so anymore
+pub fn spawn_stdin(data: &'static Data, host: &'static Host, shutdown: &'static Shutdown) {
+    thread::spawn(move || {
+        serve(io::stdin().lock(), io::stdout(), data, host, shutdown);
+        shutdown.request();
+    });
+}
+
+/// Accepts connections on the unix socket, each one gets its own console<br>
+/// A socket left over from a previous run is replaced
+pub fn spawn_socket(path: &Path, data: &'static Data, host: &'static Host, shutdown: &'static Shutdown) -> io::Result<()> {
+    listener::socket(path, "admin connection", move |reader, writer| serve(BufReader::new(reader), writer, data, host, shutdown))?;
+    info!("Admin console listening on {}", path.display());
+    Ok(())
+}
+
+fn serve(input: impl BufRead, mut output: impl Write, data: &'static Data, host: &'static Host, shutdown: &Shutdown) {
+    let _ = write!(output, "> ").and_then(|_| output.flush());
+
+    for line in input.lines() {
+        let Ok(line) = line else {
+            return;
+        };
+
+        match execute(line.trim(), data, host, shutdown, &mut output) {
+            Ok(true) => return,
+            Ok(false) => (),
+            Err(e) => {
+                let _ = writeln!(output, "error: {}", e);
+            }
+        }
+
+        if write!(output, "> ").and_then(|_| output.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Runs a single command, returns true if the console should be closed
+fn execute(line: &str, data: &Data, host: &Host, shutdown: &Shutdown, out: &mut impl Write) -> Result<bool, String> {
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else {
+        return Ok(false);
+    };
+    let args: Vec<&str> = words.collect();
+
+    let io = |e: io::Error| e.to_string();
+    match (command, args.as_slice()) {
+        ("help", _) => writeln!(out, "{}", HELP).map_err(io)?,
+        ("quit" | "exit", _) => return Ok(true),
+        ("shutdown", []) => {
+            shutdown.request();
+            return Ok(true);
+        },
+        ("plugins", []) => {
+            let loaded = host.plugins();
+            for plugin in data.plugins() {
+                let state = if plugin.is_online() { "online" } else { "offline" };
+                let coms = if plugin.coms_are_func() { ", interact by function" } else if plugin.coms_are_channel() { ", interact by channel" } else { "" };
+                write!(out, "{} {} {}{}", plugin.name, plugin.version, state, coms).map_err(io)?;
+                if let Some(lib) = loaded.iter().find(|lib| lib.registered.contains(&plugin.name)) {
+                    let unhealthy = if lib.unhealthy.is_some() { ", unhealthy" } else if lib.updating { ", updating" } else { "" };
+                    write!(out, " (library {}{})", lib.name, unhealthy).map_err(io)?;
+                }
+                writeln!(out).map_err(io)?;
+            }
+
+            let registered: Vec<String> = data.plugins().into_iter().map(|p| p.name).collect();
+            for lib in loaded.iter().filter(|lib| !lib.registered.iter().any(|n| registered.contains(n))) {
+                writeln!(out, "library {} from {}, no plugin registered", lib.name, lib.path.display()).map_err(io)?;
+            }
+        },
+        ("keys", []) | ("keys", [_]) => {
+            let pattern = args.first().map(|glob| KeyPattern::Glob(glob.to_string())).unwrap_or(KeyPattern::All);
+            for info in data.list_keys(&pattern) {
+                writeln!(out, "{} {} ({})", info.key, info.value_type, info.owner).map_err(io)?;
+            }
+        },
+        ("get", [key]) => {
+            let handle = data.get_data_handle(key).ok_or(format!("{} does not exist", key))?;
+            let value = data.get_value(&handle).map_err(|_| format!("unable to read {}", key))?;
+            let metadata = data.get_metadata(&handle).unwrap_or_default();
+            writeln!(out, "{} = {}", key, metadata.display(&value)).map_err(io)?;
+        },
+        ("set", [key, ..]) if args.len() > 1 => {
+            let handle = data.get_data_handle(key).ok_or(format!("{} does not exist", key))?;
+            let text = args[1..].join(" ");
+            data.set_value(&handle, data.loader_token(), Value::Str(text.clone())).map_err(|_| format!("unable to set {} to {}", key, text))?;
+        },
+        ("interact", [plugin, methode, ..]) => {
+            let plugin = data.get_plugin(&plugin.to_string()).ok_or(format!("{} is not registered", plugin))?;
+            let answer = plugin.interact(methode.to_string(), args[2..].join(" ")).map_err(|_| format!("{} returned an error", plugin.name))?;
+            writeln!(out, "{}", answer).map_err(io)?;
+        },
+        ("stats", []) => {
+            for (name, stats) in data.stats().all() {
+                write_stats(out, &name, &stats).map_err(io)?;
+            }
+        },
+        ("stats", [name]) => {
+            let stats = data.get_plugin_stats(name).ok_or(format!("no stats for {}", name))?;
+            write_stats(out, name, &stats).map_err(io)?;
+        },
+        ("unload", [name]) => host.unload(name)?,
+        ("reload", [name]) => host.reload(name)?,
+        ("watch", [key]) | ("watch", [key, _]) => {
+            let secs = match args.get(1) {
+                Some(secs) => secs.parse().map_err(|_| format!("{} is not a number of seconds", secs))?,
+                None => WATCH_DEFAULT_SECS
+            };
+            let handle = data.get_data_handle(key).ok_or(format!("{} does not exist", key))?;
+
+            let mut last: Option<Value> = None;
+            let end = Instant::now() + Duration::from_secs(secs);
+            while Instant::now() < end {
+                let value = data.get_value(&handle).map_err(|_| format!("{} is gone", key))?;
+                if last.as_ref() != Some(&value) {
+                    writeln!(out, "{} = {}", key, value.to_string()).map_err(io)?;
+                    out.flush().map_err(io)?;
+                    last = Some(value);
+                }
+                thread::sleep(WATCH_POLL_INTERVAL);
+            }
+        },
+        _ => return Err(format!("unknown command or wrong arguments: {}, try help", line))
+    }
+
+    Ok(false)
+}
+
+fn write_stats(out: &mut impl Write, name: &str, stats: &PluginStats) -> io::Result<()> {
+    writeln!(out, "{}: init {:?}, update {:?} in {} calls, end {:?}", name, stats.init_time, stats.update_time, stats.update_calls, stats.end_time)?;
+    writeln!(out, "  {} gets, {} sets, {} creates, interact {} served and {} made, {} errors", stats.gets, stats.sets, stats.creates, stats.interacts_served, stats.interacts_made, stats.errors)?;
+    if let Some(error) = &stats.last_error {
+        writeln!(out, "  last error: {}", error)?;
+    }
+    if let Some(reason) = &stats.unhealthy {
+        writeln!(out, "  unhealthy: {}", reason)?;
+    }
+    Ok(())
+}
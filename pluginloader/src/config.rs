@@ -0,0 +1,374 @@
+//! The loader configuration, read from `pluginloader.toml`<br>
+//! Every section and field is optional, leaving out the file entirely gives the same behaviour as before there was one.
+
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, fmt::Display, net::ToSocketAddrs, time::Duration};
+
+use log::LevelFilter;
+use plugin_sdk::KeyPattern;
+use serde::Deserialize;
+
+/// Used when no config is given on the command line, not existing is fine
+pub const DEFAULT_CONFIG_PATH: &str = "pluginloader.toml";
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub plugins: PluginsConfig,
+    pub scheduler: SchedulerConfig,
+    pub logging: LoggingConfig,
+    pub persistence: PersistenceConfig,
+    pub admin: AdminConfig,
+    pub http: HttpConfig,
+    pub websocket: WebsocketConfig,
+    pub remote: RemoteConfig,
+    pub replication: ReplicationConfig,
+    pub watchdog: WatchdogConfig,
+    /// Settings for the individual plugins, `[plugin.<name>]`, where name is the file name without `lib` and the extension
+    pub plugin: HashMap<String, toml::Table>
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginsConfig {
+    /// Directories to load plugins from, `lib` if not set
+    pub paths: Option<Vec<PathBuf>>,
+    /// If not empty only these plugins are loaded
+    pub enabled: Vec<String>,
+    /// These plugins are never loaded
+    pub disabled: Vec<String>,
+    /// These plugins are initialized first, in this order. All others follow sorted by name
+    pub order: Vec<String>
+}
+
+impl PluginsConfig {
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.paths.clone().unwrap_or_else(|| vec![PathBuf::from("lib")])
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    /// Time between update calls in milliseconds, if not set update is called once
+    pub update_interval_ms: Option<u64>,
+    /// Overrides update_interval_ms for single plugins
+    pub intervals: HashMap<String, u64>,
+    /// Shut down after this many seconds, if not set we run until all update calls are done
+    pub run_for_secs: Option<u64>
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// One of off, error, warn, info, debug, trace
+    pub level: String,
+    /// Log into this file in addition to stderr
+    pub file: Option<PathBuf>,
+    /// Levels for single plugins, by their library name (like `[plugin.<name>]`)
+    pub plugins: HashMap<String, String>,
+    /// Writes trace spans into this file, in the Chrome trace format
+    pub trace: Option<PathBuf>
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig { level: "info".to_string(), file: None, plugins: HashMap::new(), trace: None }
+    }
+}
+
+impl LoggingConfig {
+    pub fn level_filter(&self) -> Option<LevelFilter> {
+        self.level.parse().ok()
+    }
+
+    /// The plugin levels that are valid
+    pub fn plugin_filters(&self) -> HashMap<String, LevelFilter> {
+        self.plugins.iter().filter_map(|(name, level)| Some((name.clone(), level.parse().ok()?))).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceConfig {
+    /// Where values flagged with persist are saved to, `datastore.json` if not set<br>
+    /// An empty path takes no snapshots
+    pub snapshot: Option<PathBuf>,
+    /// Seconds between periodic snapshots
+    pub snapshot_interval_secs: u64,
+    /// Records all changes to the Datastore into this file
+    pub journal: Option<PathBuf>,
+    /// Replays a recorded journal while the plugins run
+    pub replay: Option<PathBuf>,
+    /// Creates the values in this json/toml file before the plugins are initialized
+    pub preload: Option<PathBuf>,
+    /// Writes the whole store into this json/toml file before shutting down
+    pub export: Option<PathBuf>
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        PersistenceConfig { snapshot: Some(PathBuf::from("datastore.json")), snapshot_interval_secs: 60, journal: None, replay: None, preload: None, export: None }
+    }
+}
+
+impl PersistenceConfig {
+    /// None if snapshots are turned off
+    pub fn snapshot(&self) -> Option<&Path> {
+        self.snapshot.as_deref().filter(|path| !path.as_os_str().is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Read admin commands from stdin
+    pub stdin: bool,
+    /// Accept admin connections on this unix socket
+    pub socket: Option<PathBuf>
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Serve the REST api
+    pub enabled: bool,
+    /// Address to serve on, localhost only by default
+    pub bind: String,
+    /// Has to be sent as `Authorization: Bearer <token>` to write values and interact with plugins<br>
+    /// Without one these requests are refused
+    pub token: Option<String>,
+    /// Threads answering requests
+    pub workers: usize,
+    /// Serve /metrics for Prometheus
+    pub metrics: bool
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig { enabled: false, bind: "127.0.0.1:8080".to_string(), token: None, workers: 4, metrics: true }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebsocketConfig {
+    /// Stream changes to subscribed clients
+    pub enabled: bool,
+    /// Address to listen on, localhost only by default
+    pub bind: String,
+    /// Most updates per second a subscription gets for each key, for subscriptions that don't ask for less
+    pub max_rate: f64
+}
+
+impl Default for WebsocketConfig {
+    fn default() -> Self {
+        WebsocketConfig { enabled: false, bind: "127.0.0.1:8081".to_string(), max_rate: 20.0 }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteConfig {
+    /// Accept remote plugins on this tcp address, like 127.0.0.1:7070
+    pub bind: Option<String>,
+    /// Accept remote plugins on this unix socket
+    pub socket: Option<PathBuf>
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// Remote plugin address of the loader to mirror values from
+    pub peer: Option<String>,
+    /// Mirrors all values owned by these plugins on the peer
+    pub plugins: Vec<String>,
+    /// Mirrors all values matching these globs, with plugins empty as well everything is mirrored
+    pub keys: Vec<String>
+}
+
+impl ReplicationConfig {
+    /// If the value is selected for mirroring
+    pub fn selects(&self, key: &str, owner: &str) -> bool {
+        (self.plugins.is_empty() && self.keys.is_empty())
+            || self.plugins.iter().any(|plugin| plugin == owner)
+            || self.keys.iter().any(|glob| KeyPattern::Glob(glob.clone()).matches(key))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogConfig {
+    /// Milliseconds init may take, no deadline if not set
+    pub init_ms: Option<u64>,
+    /// Milliseconds a single update (or config_changed) call may take
+    pub update_ms: Option<u64>,
+    /// Milliseconds end (and shutdown) may take, so shutting down can't hang on a plugin<br>
+    /// 5000 if not set, 0 for no deadline
+    pub end_ms: Option<u64>,
+    /// Milliseconds an interact call between plugins may take
+    pub interact_ms: Option<u64>
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig { init_ms: None, update_ms: None, end_ms: Some(5000), interact_ms: None }
+    }
+}
+
+impl WatchdogConfig {
+    pub fn init(&self) -> Option<Duration> {
+        self.init_ms.map(Duration::from_millis)
+    }
+
+    pub fn update(&self) -> Option<Duration> {
+        self.update_ms.map(Duration::from_millis)
+    }
+
+    pub fn end(&self) -> Option<Duration> {
+        self.end_ms.filter(|&ms| ms > 0).map(Duration::from_millis)
+    }
+
+    pub fn interact(&self) -> Option<Duration> {
+        self.interact_ms.map(Duration::from_millis)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// All problems found by validate
+    Invalid(Vec<String>)
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Unable to read config {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Unable to parse config {}:\n{}", path.display(), e),
+            ConfigError::Invalid(problems) => {
+                writeln!(f, "The config is invalid:")?;
+                for problem in problems {
+                    writeln!(f, "  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config from the file<br>
+    /// If the file does not exist and must_exist is false the default config is returned
+    pub fn load(path: &Path, must_exist: bool) -> Result<Config, ConfigError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !must_exist => return Ok(Config::default()),
+            Err(e) => return Err(ConfigError::Io(path.to_path_buf(), e))
+        };
+
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    /// Checks everything that parsing can't, collecting all problems at once
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = vec![];
+
+        // Only if they were set, without lib there are just no plugins
+        if let Some(paths) = &self.plugins.paths {
+            if paths.is_empty() {
+                problems.push("plugins.paths is empty, no plugins could be loaded".to_string());
+            }
+            for path in paths {
+                if !path.is_dir() {
+                    problems.push(format!("plugins.paths: {} is not a directory", path.display()));
+                }
+            }
+        }
+        for name in &self.plugins.enabled {
+            if self.plugins.disabled.contains(name) {
+                problems.push(format!("plugins: {} is both enabled and disabled", name));
+            }
+        }
+
+        if self.scheduler.update_interval_ms == Some(0) {
+            problems.push("scheduler.update_interval_ms has to be greater than 0, leave it out to call update once".to_string());
+        }
+        for (name, interval) in &self.scheduler.intervals {
+            if *interval == 0 {
+                problems.push(format!("scheduler.intervals.{} has to be greater than 0", name));
+            }
+        }
+
+        if self.logging.level_filter().is_none() {
+            problems.push(format!("logging.level: unknown level {:?}, expected one of off, error, warn, info, debug, trace", self.logging.level));
+        }
+        for (name, level) in &self.logging.plugins {
+            if level.parse::<LevelFilter>().is_err() {
+                problems.push(format!("logging.plugins.{}: unknown level {:?}, expected one of off, error, warn, info, debug, trace", name, level));
+            }
+        }
+
+        if self.persistence.snapshot_interval_secs == 0 {
+            problems.push("persistence.snapshot_interval_secs has to be greater than 0".to_string());
+        }
+        for (field, path) in [("preload", &self.persistence.preload), ("replay", &self.persistence.replay)] {
+            if let Some(path) = path {
+                if !path.is_file() {
+                    problems.push(format!("persistence.{}: {} does not exist", field, path.display()));
+                }
+            }
+        }
+
+        if self.http.enabled && self.http.bind.to_socket_addrs().is_err() {
+            problems.push(format!("http.bind: {} is not an address to listen on, like 127.0.0.1:8080", self.http.bind));
+        }
+        if self.http.workers == 0 {
+            problems.push("http.workers has to be greater than 0".to_string());
+        }
+
+        if self.websocket.enabled && self.websocket.bind.to_socket_addrs().is_err() {
+            problems.push(format!("websocket.bind: {} is not an address to listen on, like 127.0.0.1:8081", self.websocket.bind));
+        }
+        if self.websocket.max_rate.is_nan() || self.websocket.max_rate <= 0.0 {
+            problems.push("websocket.max_rate has to be greater than 0".to_string());
+        }
+
+        if let Some(bind) = &self.remote.bind {
+            if bind.to_socket_addrs().is_err() {
+                problems.push(format!("remote.bind: {} is not an address to listen on, like 127.0.0.1:7070", bind));
+            }
+        }
+        if let Some(peer) = &self.replication.peer {
+            if peer.to_socket_addrs().is_err() {
+                problems.push(format!("replication.peer: {} is not an address to connect to, like 127.0.0.1:7070", peer));
+            }
+            if self.remote.bind.as_ref() == Some(peer) {
+                problems.push("replication.peer is our own remote.bind, the loader can't mirror itself".to_string());
+            }
+        }
+
+        let watchdog = &self.watchdog;
+        for (field, deadline) in [("init_ms", watchdog.init_ms), ("update_ms", watchdog.update_ms), ("interact_ms", watchdog.interact_ms)] {
+            if deadline == Some(0) {
+                problems.push(format!("watchdog.{} has to be greater than 0", field));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// Returns if the plugin with this name should be loaded
+    pub fn is_enabled(&self, name: &str) -> bool {
+        (self.plugins.enabled.is_empty() || self.plugins.enabled.iter().any(|n| n == name)) && !self.plugins.disabled.iter().any(|n| n == name)
+    }
+
+    /// Update interval in milliseconds for the plugin, None if update should only be called once
+    pub fn update_interval(&self, name: &str) -> Option<u64> {
+        self.scheduler.intervals.get(name).copied().or(self.scheduler.update_interval_ms)
+    }
+}
@@ -0,0 +1,1116 @@
+use std::{sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, LockResult, Mutex, OnceLock, atomic::{self, Ordering, AtomicBool, AtomicU64}, Arc, mpsc::{self, SyncSender, Receiver, TrySendError, TryRecvError, RecvTimeoutError}}, collections::{HashMap, HashSet, hash_map::DefaultHasher, VecDeque}, hash::{Hash, Hasher}, time::{Duration, Instant, SystemTime}};
+
+use arc_swap::ArcSwap;
+use rand::{Rng, distributions::Alphanumeric};
+use plugin_sdk::{Datastore, Plugin, Value, AccessToken, DataHandle, KeyPattern, KeyInfo, Metadata, RangePolicy, HistoryLimit, HistoryRange, HistorySample, PluginConfig, LogLevel, LogRecord, PluginStats};
+
+use crate::{logging, stats::{self, CallHook, Counters, Op, Stats}, segmented::SegmentedVec, value_store::ValueStore, expression::Expr, journal::{Journal, JournalEntry, JournalEvent}};
+
+/// The Datastore handed to all plugins<br>
+/// <br>
+/// Values are stored append only, so reading a value via a DataHandle never waits on a lock.<br>
+/// The key_map is only required to resolve keys into handles, and is locked on creation.
+pub struct Data {
+    key_map: RwLock<HashMap<String, usize>>,
+    plugins: RwLock<HashMap<String, InteralPlugin>>,
+    data_store: SegmentedVec<DataContainer>,
+    batch_seq: AtomicU64,
+    batch_lock: Mutex<()>,
+    initializing: AtomicBool,
+    restored: Mutex<HashMap<String, Value>>,
+    journal: OnceLock<Journal>,
+    subscribers: ArcSwap<Vec<Subscriber>>,
+    next_subscriber: AtomicU64,
+    loader_token: AccessToken,
+    key_map_read_wait: LockWait,
+    key_map_write_wait: LockWait,
+    batch_wait: LockWait,
+    plugin_configs: RwLock<HashMap<String, PluginConfig>>,
+    stats: Stats,
+    /// Counters of the plugin each access token belongs to, so counting calls by token never locks
+    token_counters: ArcSwap<HashMap<AccessToken, Arc<Counters>>>,
+    interact_deadline: OnceLock<Duration>,
+    /// The libraries each library got a Plugin of with get_plugin
+    dependencies: RwLock<HashMap<String, HashSet<String>>>
+}
+
+/// Name the loader is registered under, no plugin can use it
+pub const LOADER_NAME: &str = "loader";
+
+/// Records logged with a token that belongs to no plugin are tagged with this
+const UNREGISTERED_NAME: &str = "unregistered";
+
+/// Owner token of mirrored values, never handed out
+const MIRROR_TOKEN: AccessToken = AccessToken::new(String::new());
+
+/// Length of the tokens handed out, in alphanumeric characters
+const TOKEN_LENGTH: usize = 32;
+
+impl Datastore for Data {
+    fn create_value(&self, key: String, access_token: &AccessToken, val_type: Value) -> Result<DataHandle,()> {
+        self.create_value_with_metadata(key, access_token, val_type, Metadata::default())
+    }
+
+    fn create_value_with_metadata(&self, key: String, access_token: &AccessToken, val_type: Value, metadata: Metadata) -> Result<DataHandle,()> {
+        let _span = tracing::trace_span!("create_value", key).entered();
+        let name = key.clone();
+        self.account(Op::Create, Some(access_token), || format!("create_value {}", name), || {
+            self.insert_container(key, self.owner(access_token)?, val_type, metadata, None)
+        })
+    }
+
+    fn create_derived_value(&self, key: String, access_token: &AccessToken, expression: &str, metadata: Metadata) -> Result<DataHandle,()> {
+        let _span = tracing::trace_span!("create_derived_value", key, expression).entered();
+        let name = key.clone();
+        self.account(Op::Create, Some(access_token), || format!("create_derived_value {}", name), || {
+            let expr = Expr::parse(expression, |key| self.get_data_handle(key)).map_err(|e| {
+                log::warn!("Unable to create derived value {}: {}", key, e);
+            })?;
+            let initial = expr.eval(&|handle| self.read_input(handle))?;
+            let inputs = expr.inputs();
+
+            let handle = self.insert_container(key, self.owner(access_token)?, initial, metadata, Some(Derived { expr, recompute_lock: Mutex::new(()) }))?;
+
+            for input in inputs {
+                if let Ok(cont) = self.get_container(&input) {
+                    cont.dependents.rcu(|dependents| {
+                        let mut dependents = Vec::clone(dependents);
+                        dependents.push(handle.index);
+                        dependents
+                    });
+                }
+            }
+
+            // An input might have changed before we were added to the dependents
+            self.recompute(self.get_container(&handle)?);
+            Ok(handle)
+        })
+    }
+
+    fn get_metadata(&self, handle: &DataHandle) -> Result<Metadata, ()> {
+        let _span = tracing::trace_span!("get_metadata", key = self.key_of(handle)).entered();
+        Ok(self.get_container(handle)?.metadata.clone())
+    }
+
+    fn set_value(&self, handle: &DataHandle, access_token: &AccessToken, val: Value) -> Result<(),()> {
+        let _span = tracing::trace_span!("set_value", key = self.key_of(handle)).entered();
+        self.account(Op::Set, Some(access_token), || format!("set_value {}", self.key_of(handle)), || {
+            let cont = self.get_writable_container(handle, access_token)?;
+            let val = cont.constrain(val)?;
+            self.write(cont, |store| store.update(val))
+        })
+    }
+
+    fn get_value(&self, handle: &DataHandle) -> Result<Value,()> {
+        let _span = tracing::trace_span!("get_value", key = self.key_of(handle)).entered();
+        self.account(Op::Get, None, || format!("get_value {}", self.key_of(handle)), || {
+            Ok(self.get_container(handle)?.value.read())
+        })
+    }
+
+    fn set_values(&self, access_token: &AccessToken, values: Vec<(&DataHandle, Value)>) -> Result<(), ()> {
+        let _span = tracing::trace_span!("set_values", count = values.len()).entered();
+        self.account(Op::Set, Some(access_token), || "set_values".to_string(), || {
+            // Resolving and converting everything first, so we don't fail halfway through
+            let mut prepared = Vec::with_capacity(values.len());
+            for (handle, val) in values {
+                let cont = self.get_writable_container(handle, access_token)?;
+                prepared.push((cont, cont.constrain(val)?));
+            }
+
+            let _guard = self.batch_wait.time(|| self.batch_lock.lock()).unwrap_or_else(|e| e.into_inner());
+            // Only the values are written while get_values waits, recording and recomputing follows once the batch is done
+            let written: Vec<&DataContainer> = {
+                let _batch = Batch::begin(&self.batch_seq);
+                prepared.into_iter().filter_map(|(cont, val)| cont.write_if(|store| store.update(val), |_| true).ok().map(|_| cont)).collect()
+            };
+
+            for cont in written {
+                self.changed(cont);
+            }
+            Ok(())
+        })
+    }
+
+    fn get_values(&self, handles: &[DataHandle]) -> Result<Vec<Value>, ()> {
+        let _span = tracing::trace_span!("get_values", count = handles.len()).entered();
+        self.account(Op::Get, None, || "get_values".to_string(), || {
+            let conts = handles.iter().map(|handle| self.get_container(handle)).collect::<Result<Vec<_>, ()>>()?;
+
+            loop {
+                let seq = self.batch_seq.load(Ordering::Acquire);
+                if seq % 2 == 1 {
+                    // A batch is being written right now
+                    std::hint::spin_loop();
+                    continue;
+                }
+
+                let values: Vec<Value> = conts.iter().map(|cont| cont.value.read()).collect();
+
+                atomic::fence(Ordering::Acquire);
+                if self.batch_seq.load(Ordering::Relaxed) == seq {
+                    return Ok(values);
+                }
+            }
+        })
+    }
+
+    fn compare_and_swap(&self, handle: &DataHandle, access_token: &AccessToken, current: Value, new: Value) -> Result<bool, ()> {
+        let _span = tracing::trace_span!("compare_and_swap", key = self.key_of(handle)).entered();
+        self.account(Op::Set, Some(access_token), || format!("compare_and_swap {}", self.key_of(handle)), || {
+            let cont = self.get_writable_container(handle, access_token)?;
+            let new = cont.constrain(new)?;
+            self.write_if(cont, |store| store.compare_and_swap(current, new), |swapped| *swapped)
+        })
+    }
+
+    fn fetch_add(&self, handle: &DataHandle, access_token: &AccessToken, val: Value) -> Result<Value, ()> {
+        let _span = tracing::trace_span!("fetch_add", key = self.key_of(handle)).entered();
+        self.account(Op::Set, Some(access_token), || format!("fetch_add {}", self.key_of(handle)), || {
+            let cont = self.get_writable_container(handle, access_token)?;
+            if cont.metadata.min.is_none() && cont.metadata.max.is_none() {
+                return self.write(cont, |store| store.fetch_add(val));
+            }
+
+            // With a range we have to check the result before storing it
+            let add = cont.value.coerce(val)?;
+            self.write(cont, |store| store.fetch_update(|current| match (current, &add) {
+                (Value::Int(i), Value::Int(a)) => cont.constrain(Value::Int(i.wrapping_add(*a))),
+                (Value::Float(f), Value::Float(a)) => cont.constrain(Value::Float(f + a)),
+                _ => Err(())
+            }))
+        })
+    }
+
+    fn toggle(&self, handle: &DataHandle, access_token: &AccessToken) -> Result<bool, ()> {
+        let _span = tracing::trace_span!("toggle", key = self.key_of(handle)).entered();
+        self.account(Op::Set, Some(access_token), || format!("toggle {}", self.key_of(handle)), || {
+            self.write(self.get_writable_container(handle, access_token)?, |store| store.toggle())
+        })
+    }
+
+    fn append(&self, handle: &DataHandle, access_token: &AccessToken, text: &str) -> Result<(), ()> {
+        let _span = tracing::trace_span!("append", key = self.key_of(handle)).entered();
+        self.account(Op::Set, Some(access_token), || format!("append {}", self.key_of(handle)), || {
+            self.write(self.get_writable_container(handle, access_token)?, |store| store.append(text))
+        })
+    }
+
+    fn get_history(&self, handle: &DataHandle, range: HistoryRange) -> Result<Vec<HistorySample>, ()> {
+        let _span = tracing::trace_span!("get_history", key = self.key_of(handle)).entered();
+        self.account(Op::Get, None, || format!("get_history {}", self.key_of(handle)), || {
+            let container = self.get_container(handle)?;
+            let mut history = container.history.as_ref().ok_or(())?.lock().map_err(|_| ())?;
+            // A value that isn't written anymore would otherwise keep samples past the duration forever
+            if let Some(limit) = container.metadata.history {
+                trim_history(&mut history, limit, SystemTime::now());
+            }
+
+            Ok(match range {
+                HistoryRange::All => history.iter().cloned().collect(),
+                HistoryRange::Last(n) => history.iter().skip(history.len().saturating_sub(n)).cloned().collect(),
+                HistoryRange::Since(time) => history.iter().filter(|sample| sample.time >= time).cloned().collect(),
+            })
+        })
+    }
+
+    fn list_keys(&self, pattern: &KeyPattern) -> Vec<KeyInfo> {
+        let _span = tracing::trace_span!("list_keys", pattern = ?pattern).entered();
+        let map = self.read_key_map().expect("Unable to read key map");
+
+        let mut keys: Vec<KeyInfo> = map.iter().filter(|(key, _)| pattern.matches(key)).filter_map(|(key, index)| {
+            let cont = self.data_store.get(*index)?;
+            Some(KeyInfo {
+                key: key.clone(),
+                owner: cont.owner_name.clone(),
+                value_type: cont.value.value_type(),
+                handle: DataHandle { index: *index, name_hash: cont.name_hash.load(Ordering::Acquire) }
+            })
+        }).collect();
+
+        keys.sort_by(|a, b| a.key.cmp(&b.key));
+        keys
+    }
+
+    fn register_plugin(&self, plugin: Plugin) -> Option<AccessToken> {
+        let _span = tracing::trace_span!("register_plugin", plugin = plugin.name).entered();
+        let mut l = self.plugins.write().expect("Unable to write to plugin list");
+        if l.contains_key(&plugin.name) {
+            return None;
+        }
+
+        // Registered in init of a library, or by a remote plugin that has no library
+        let current = stats::current_name();
+        let library = current.clone().unwrap_or(plugin.name.clone());
+        let counters = self.stats.counters(&library);
+        // Like an init that was abandoned after its deadline
+        if current.is_some() && counters.unhealthy().is_some() {
+            log::warn!("Not registering plugin {}, its library {} is unhealthy", plugin.name, library);
+            return None;
+        }
+
+        let access_token = random_token();
+        self.record(&plugin.name, || JournalEvent::Register { version: plugin.version.clone() });
+        self.token_counters.rcu(|tokens| {
+            let mut tokens = HashMap::clone(tokens);
+            tokens.insert(access_token.clone(), counters.clone());
+            tokens
+        });
+        l.insert(plugin.name.clone(), InteralPlugin { plugin, library, access_token: access_token.clone(), switchoff_handle: Arc::new(AtomicBool::new(false)) });
+        Some(access_token)
+    }
+
+    fn get_plugin(&self, name: &String) -> Option<Plugin> {
+        let _span = tracing::trace_span!("get_plugin", plugin = name).entered();
+        let r = self.plugins.read().expect("Unable to read plugin list");
+        if let Some(plugin) = r.get(name) {
+            if let Some(caller) = stats::current_name().filter(|caller| caller != &plugin.library) {
+                self.add_dependency(caller, &plugin.library);
+            }
+            return Some(self.hand_out(plugin));
+        }
+
+        None
+    }
+
+    fn get_plugin_config(&self, name: &str) -> PluginConfig {
+        self.plugin_configs.read().expect("Unable to read plugin configs").get(name).cloned().unwrap_or_default()
+    }
+
+    fn log(&self, access_token: &AccessToken, record: LogRecord) {
+        let (name, library, version) = self.get_plugin_identity(access_token).unwrap_or((UNREGISTERED_NAME.to_string(), UNREGISTERED_NAME.to_string(), "?".to_string()));
+        logging::log_plugin(&library, &name, &version, record);
+    }
+
+    fn log_enabled(&self, access_token: &AccessToken, level: LogLevel) -> bool {
+        let l = self.plugins.read().expect("Unable to read plugin list");
+        let library = l.values().find(|plugin| &plugin.access_token == access_token).map_or(UNREGISTERED_NAME, |plugin| plugin.library.as_str());
+        logging::plugin_enabled(library, level)
+    }
+
+    fn get_plugin_stats(&self, name: &str) -> Option<PluginStats> {
+        self.stats.get(name)
+    }
+
+    fn deregister_plugin(&self, access_token: &AccessToken) -> bool {
+        let _span = tracing::trace_span!("deregister_plugin").entered();
+        let mut l = self.plugins.write().expect("Unable to write to plugin list");
+        let mut index: Option<String> = None;
+
+        for (name, plugin) in l.iter() {
+            if &plugin.access_token == access_token {
+                index = Some(name.clone());
+            }
+        }
+
+        if let Some(index) = index {
+            if let Some(con) = l.remove(&index) {
+                // Interacting with the plugin is no longer possible
+                con.switchoff_handle.store(true, Ordering::Release); 
+                self.token_counters.rcu(|tokens| {
+                    let mut tokens = HashMap::clone(tokens);
+                    tokens.remove(access_token);
+                    tokens
+                });
+                self.record(&index, || JournalEvent::Deregister);
+            }
+        }
+
+        drop(l);
+
+        // We should also unload the propertys the plugins created
+        
+
+        true
+    }
+
+    fn delete_value(&self, handle: &DataHandle, access_token: &AccessToken) -> Result<(), ()> {
+        let _span = tracing::trace_span!("delete_value", key = self.key_of(handle)).entered();
+        let mut map = self.write_key_map().map_err(|_| ())?;
+        let cont = self.get_owned_container(handle, access_token)?;
+
+        cont.removed.store(true, Ordering::Release);
+        map.remove(cont.name.load().as_str());
+        self.record(&cont.owner_name, || JournalEvent::Delete { key: cont.name.load().to_string() });
+        self.remove_dependents(&mut map, cont);
+
+        // The slot is not reused, as the store is append only
+        Ok(())
+    }
+
+    fn rename_value(&self, handle: &DataHandle, access_token: &AccessToken, new_key: String) -> Result<DataHandle, ()> {
+        let _span = tracing::trace_span!("rename_value", key = self.key_of(handle), new_key).entered();
+        let mut map = self.write_key_map().map_err(|_| ())?;
+        let cont = self.get_owned_container(handle, access_token)?;
+
+        if map.contains_key(&new_key) {
+            return Err(());
+        }
+
+        let name_hash = hash_name(&new_key);
+
+        map.remove(cont.name.load().as_str());
+        map.insert(new_key.clone(), handle.index);
+        self.record(&cont.owner_name, || JournalEvent::Rename { key: cont.name.load().to_string(), new_key: new_key.clone() });
+        cont.name.store(Arc::new(new_key));
+        cont.name_hash.store(name_hash, Ordering::Release);
+
+        Ok(DataHandle { index: handle.index, name_hash })
+    }
+
+    fn get_data_handle(&self, key: &str) -> Option<DataHandle> {
+        let _span = tracing::trace_span!("get_data_handle", key).entered();
+        if let Ok(map) = self.read_key_map() {
+            if let Some(addr) = map.get(key) {
+                if let Some(item) = self.data_store.get(*addr) {
+
+                    return Some(DataHandle { index: *addr, name_hash: item.name_hash.load(Ordering::Acquire) });
+                }
+            }
+
+        }
+
+        None
+    }
+}
+
+impl Data {
+    pub fn new() -> Data {
+        let loader_token = random_token();
+        let loader = InteralPlugin {
+            library: LOADER_NAME.to_string(),
+            plugin: Plugin::new(LOADER_NAME.to_string(), env!("CARGO_PKG_VERSION").to_string(), None),
+            access_token: loader_token.clone(),
+            switchoff_handle: Arc::new(AtomicBool::new(false))
+        };
+        let stats = Stats::new();
+        let token_counters = ArcSwap::from_pointee(HashMap::from([(loader_token.clone(), stats.counters(LOADER_NAME))]));
+
+        Data {key_map: RwLock::new(HashMap::<String,usize>::new()), plugins: RwLock::new(HashMap::from([(LOADER_NAME.to_string(), loader)])), data_store: SegmentedVec::new(), batch_seq: AtomicU64::new(0), batch_lock: Mutex::new(()), initializing: AtomicBool::new(true), restored: Mutex::new(HashMap::new()), journal: OnceLock::new(), subscribers: ArcSwap::from_pointee(vec![]), next_subscriber: AtomicU64::new(0), loader_token, key_map_read_wait: LockWait::default(), key_map_write_wait: LockWait::default(), batch_wait: LockWait::default(), plugin_configs: RwLock::new(HashMap::new()), stats, token_counters, interact_deadline: OnceLock::new(), dependencies: RwLock::new(HashMap::new())}
+    }
+
+    /// Access token of the loader, for values the loader owns itself<br>
+    /// Also the admin token, it is allowed to change, delete and rename values of every plugin
+    pub fn loader_token(&self) -> &AccessToken {
+        &self.loader_token
+    }
+
+    /// Replaces the config sections of the plugins<br>
+    /// Returns the names of all plugins whose section changed (including sections that were removed)
+    pub fn set_plugin_configs(&self, sections: HashMap<String, toml::Table>) -> Vec<String> {
+        let new: HashMap<String, PluginConfig> = sections.into_iter().map(|(name, table)| (name, PluginConfig::new(table))).collect();
+        let mut configs = self.plugin_configs.write().expect("Unable to write plugin configs");
+
+        let mut changed: Vec<String> = new.iter().filter(|(name, config)| configs.get(*name) != Some(config)).map(|(name, _)| name.clone()).collect();
+        changed.extend(configs.keys().filter(|name| !new.contains_key(*name)).cloned());
+        changed.sort();
+
+        *configs = new;
+        changed
+    }
+
+    /// All registered plugins, sorted by name
+    pub fn plugins(&self) -> Vec<Plugin> {
+        let r = self.plugins.read().expect("Unable to read plugin list");
+        let mut plugins: Vec<Plugin> = r.values().map(|p| self.hand_out(p)).collect();
+        plugins.sort_by(|a, b| a.name.cmp(&b.name));
+        plugins
+    }
+
+    /// Deregisters the plugin (if it did not do so itself) and deletes all values it owns<br>
+    /// Used when a plugin is unloaded, returns the number of deleted values
+    pub fn purge_plugin(&self, name: &str) -> usize {
+        if name == LOADER_NAME {
+            return 0;
+        }
+
+        let token = self.plugins.read().expect("Unable to read plugin list").get(name).map(|p| p.access_token.clone());
+        if let Some(token) = token {
+            self.deregister_plugin(&token);
+        }
+
+        let mut map = self.write_key_map().expect("Unable to write key map");
+        let owned: Vec<String> = map.iter().filter(|(_, index)| self.data_store.get(**index).is_some_and(|cont| cont.owner_name == name && !cont.mirrored)).map(|(key, _)| key.clone()).collect();
+
+        for key in &owned {
+            if let Some(cont) = map.remove(key).and_then(|index| self.data_store.get(index)) {
+                cont.removed.store(true, Ordering::Release);
+                self.record(name, || JournalEvent::Delete { key: key.clone() });
+                self.remove_dependents(&mut map, cont);
+            }
+        }
+
+        owned.len()
+    }
+
+    /// Time spent waiting for each lock of the Datastore, by name
+    pub fn lock_waits(&self) -> [(&'static str, &LockWait); 3] {
+        [("key_map_read", &self.key_map_read_wait), ("key_map_write", &self.key_map_write_wait), ("batch", &self.batch_wait)]
+    }
+
+    fn read_key_map(&self) -> LockResult<RwLockReadGuard<'_, HashMap<String, usize>>> {
+        self.key_map_read_wait.time(|| self.key_map.read())
+    }
+
+    fn write_key_map(&self) -> LockResult<RwLockWriteGuard<'_, HashMap<String, usize>>> {
+        self.key_map_write_wait.time(|| self.key_map.write())
+    }
+
+    /// Creates a value mirrored from a peer loader, owned by the plugin there<br>
+    /// It can't be changed locally, not even with the admin token. Returns false if the key exists already
+    pub fn create_mirror(&self, key: String, owner: String, val: Value, mut metadata: Metadata) -> bool {
+        // The peer persists it, and read only is enforced anyway
+        metadata.persist = false;
+        metadata.read_only_after_init = false;
+        self.insert_container(key, Owner { token: MIRROR_TOKEN, name: owner, mirrored: true }, val, metadata, None).is_ok()
+    }
+
+    /// Sets a mirrored value, returns false if the key is not mirrored
+    pub fn set_mirrored(&self, key: &str, val: Value) -> bool {
+        let Some(cont) = self.get_data_handle(key).and_then(|handle| self.get_container(&handle).ok()).filter(|cont| cont.mirrored) else {
+            return false;
+        };
+        cont.constrain(val).and_then(|val| self.write(cont, |store| store.update(val))).is_ok()
+    }
+
+    /// Deletes a mirrored value, returns false if the key is not mirrored
+    pub fn delete_mirrored(&self, key: &str) -> bool {
+        let mut map = self.write_key_map().expect("Unable to write key map");
+        match map.get(key).and_then(|index| self.data_store.get(*index)) {
+            Some(cont) if cont.mirrored => {
+                cont.removed.store(true, Ordering::Release);
+                map.remove(key);
+                self.record(&cont.owner_name, || JournalEvent::Delete { key: key.to_string() });
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Deletes all mirrored values, once the peer is gone. Returns how many there were
+    pub fn remove_mirrors(&self) -> usize {
+        let mirrored: Vec<String> = self.list_keys(&KeyPattern::All).into_iter().map(|info| info.key).filter(|key| self.is_mirrored(key)).collect();
+        mirrored.iter().filter(|key| self.delete_mirrored(key)).count()
+    }
+
+    pub fn is_mirrored(&self, key: &str) -> bool {
+        self.get_data_handle(key).and_then(|handle| self.get_container(&handle).ok()).is_some_and(|cont| cont.mirrored)
+    }
+
+    /// Starts recording all changes into the journal<br>
+    /// Returns false if a journal was already set
+    pub fn set_journal(&self, journal: Journal) -> bool {
+        self.journal.set(journal).is_ok()
+    }
+
+    /// Receives every change from now on, the same entries the journal records<br>
+    /// At most capacity changes are kept until they are received, more are dropped and the receiver is told it lagged behind.<br>
+    /// Dropping the Changes ends the subscription
+    pub fn subscribe(&self, capacity: usize) -> Changes {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let lagged = Arc::new(AtomicBool::new(false));
+        let id = self.next_subscriber.fetch_add(1, Ordering::Relaxed);
+        self.subscribers.rcu(|subscribers| {
+            let mut subscribers = Vec::clone(subscribers);
+            subscribers.push(Subscriber { id, sender: sender.clone(), lagged: lagged.clone() });
+            subscribers
+        });
+        Changes { receiver, lagged }
+    }
+
+    /// Writes the event into the journal and sends it to the subscribers, if there are any
+    fn record(&self, plugin: &str, event: impl FnOnce() -> JournalEvent) {
+        let journal = self.journal.get();
+        let subscribers = self.subscribers.load();
+        if journal.is_none() && subscribers.is_empty() {
+            return;
+        }
+
+        let event = event();
+        if !subscribers.is_empty() {
+            let entry = JournalEntry::new(plugin, event.clone());
+            let gone: Vec<u64> = subscribers.iter().filter(|s| !s.send(entry.clone())).map(|s| s.id).collect();
+            if !gone.is_empty() {
+                self.subscribers.rcu(|subscribers| subscribers.iter().filter(|s| !gone.contains(&s.id)).cloned().collect::<Vec<_>>());
+            }
+        }
+
+        if let Some(journal) = journal {
+            journal.record(plugin, event);
+        }
+    }
+
+    /// To be called before the plugins are initialized<br>
+    /// Persisted values created later on start with the restored value instead of their initial one
+    pub fn restore(&self, values: HashMap<String, Value>) {
+        *self.restored.lock().unwrap_or_else(|e| e.into_inner()) = values;
+    }
+
+    /// Returns all values flagged with persist<br>
+    /// Restored values that were not created again this run are included, so they are not lost
+    pub fn snapshot(&self) -> HashMap<String, Value> {
+        // Same locking order as on creation, key_map first
+        let map = self.read_key_map().expect("Unable to read key map");
+        let mut values = self.restored.lock().unwrap_or_else(|e| e.into_inner()).clone();
+
+        for (key, index) in map.iter() {
+            if let Some(cont) = self.data_store.get(*index) {
+                if cont.metadata.persist {
+                    values.insert(key.clone(), cont.value.read());
+                }
+            }
+        }
+
+        values
+    }
+
+    /// To be called once all plugins ran their init<br>
+    /// From here on values flagged read_only_after_init can no longer be set
+    pub fn finish_init(&self) {
+        self.initializing.store(false, Ordering::Release);
+        self.record(LOADER_NAME, || JournalEvent::Initialized);
+    }
+
+    /// The owner of values created with this token<br>
+    /// The name is resolved now, so list_keys can still name it after the plugin deregistered
+    fn owner(&self, access_token: &AccessToken) -> Result<Owner, ()> {
+        let name = self.get_plugin_name(access_token).ok_or(())?;
+        Ok(Owner { token: access_token.clone(), name, mirrored: false })
+    }
+
+    /// Creates the container for a new value, recording the initial value in the history
+    fn insert_container(&self, key: String, owner: Owner, val_type: Value, metadata: Metadata, derived: Option<Derived>) -> Result<DataHandle,()> {
+        let value = ValueStore::from(apply_range(&metadata, val_type)?);
+        let history = metadata.history.map(|_| Mutex::new(VecDeque::new()));
+
+        if let Ok(mut map) = self.write_key_map() {
+            // We should prepend the namespace for this specific plugin...
+            // or not, too complicated for this prototype
+            if map.contains_key(&key) {
+                return Err(());
+            }
+
+            if metadata.persist && derived.is_none() {
+                if let Some(restored) = self.restored.lock().unwrap_or_else(|e| e.into_inner()).remove(&key) {
+                    // The type stays the one the plugin asked for, if the restored value doesn't fit we keep the initial value
+                    if let Ok(val) = value.coerce(restored).and_then(|val| apply_range(&metadata, val)) {
+                        value.update(val)?;
+                    }
+                }
+            }
+
+            let name_hash = hash_name(&key);
+
+            // Readers can only find the container once it is in the map, which we hold the lock for
+            let index = self.data_store.push(DataContainer {
+                name: ArcSwap::from_pointee(key.clone()),
+                value,
+                history,
+                metadata,
+                owner: owner.token,
+                owner_name: owner.name,
+                mirrored: owner.mirrored,
+                name_hash: AtomicU64::new(name_hash),
+                removed: AtomicBool::new(false),
+                derived,
+                dependents: ArcSwap::from_pointee(vec![])
+            });
+            map.insert(key, index);
+
+            let cont = self.data_store.get(index).expect("Value was just pushed");
+            cont.write_if(|_| Ok(()), |_| true).expect("Recording the initial value can not fail");
+            self.record(&cont.owner_name, || JournalEvent::Create { key: cont.name.load().to_string(), value: cont.value.read(), metadata: cont.metadata.clone() });
+
+            Ok(DataHandle { index, name_hash })
+        } else {
+            Err(())
+        }
+    }
+
+    /// Runs a write on the container, and if it changed recomputes all values derived from it
+    fn write<R>(&self, cont: &DataContainer, f: impl FnOnce(&ValueStore) -> Result<R, ()>) -> Result<R, ()> {
+        self.write_if(cont, f, |_| true)
+    }
+
+    /// Same as write, but only counts as a change if changed returns true for the result
+    fn write_if<R>(&self, cont: &DataContainer, f: impl FnOnce(&ValueStore) -> Result<R, ()>, changed: impl FnOnce(&R) -> bool) -> Result<R, ()> {
+        let (res, did_change) = cont.write_if(f, changed)?;
+        if did_change {
+            self.changed(cont);
+        }
+        Ok(res)
+    }
+
+    /// Records the new value of the container and recomputes all values derived from it
+    fn changed(&self, cont: &DataContainer) {
+        self.record(&cont.owner_name, || JournalEvent::Set { key: cont.name.load().to_string(), value: cont.value.read() });
+
+        for index in cont.dependents.load().iter() {
+            if let Some(derived) = self.data_store.get(*index) {
+                self.recompute(derived);
+            }
+        }
+    }
+
+    /// Evaluates the expression of a derived value and stores the result<br>
+    /// If it fails to evaluate (like a division by zero) the previous value is kept
+    fn recompute(&self, cont: &DataContainer) {
+        let Some(derived) = &cont.derived else {
+            return;
+        };
+        if cont.removed.load(Ordering::Acquire) {
+            return;
+        }
+
+        // Serializing recomputes, so the last one to run has seen the latest inputs
+        let _guard = derived.recompute_lock.lock().unwrap_or_else(|e| e.into_inner());
+        if let Ok(val) = derived.expr.eval(&|handle| self.read_input(handle)).and_then(|val| cont.constrain(val)) {
+            let _ = self.write(cont, |store| store.update(val));
+        }
+    }
+
+    /// Reads an input of a derived value by its slot, ignoring the name, so renamed inputs are still followed
+    fn read_input(&self, handle: &DataHandle) -> Result<Value, ()> {
+        let cont = self.data_store.get(handle.index).ok_or(())?;
+        if cont.removed.load(Ordering::Acquire) {
+            return Err(());
+        }
+        Ok(cont.value.read())
+    }
+
+    /// Deletes the values derived from a deleted value (and the ones derived from them), as they can't be computed anymore<br>
+    /// Called with the key_map locked for writing
+    fn remove_dependents(&self, map: &mut HashMap<String, usize>, cont: &DataContainer) {
+        for index in cont.dependents.load().iter() {
+            let Some(derived) = self.data_store.get(*index) else {
+                continue;
+            };
+            if derived.removed.swap(true, Ordering::AcqRel) {
+                continue;
+            }
+
+            let key = derived.name.load().to_string();
+            map.remove(&key);
+            log::warn!("Deleted derived value {}, its input {} was deleted", key, cont.name.load());
+            self.record(&derived.owner_name, || JournalEvent::Delete { key: key.clone() });
+            self.remove_dependents(map, derived);
+        }
+    }
+
+    fn add_dependency(&self, library: String, uses: &str) {
+        if self.dependencies.read().expect("Unable to read dependencies").get(&library).is_some_and(|known| known.contains(uses)) {
+            return;
+        }
+        self.dependencies.write().expect("Unable to write dependencies").entry(library).or_default().insert(uses.to_string());
+    }
+
+    /// The libraries this library got a Plugin of with get_plugin, while it was called by the loader<br>
+    /// Threads the plugin started itself are not known to belong to it
+    pub fn dependencies(&self, library: &str) -> HashSet<String> {
+        self.dependencies.read().expect("Unable to read dependencies").get(library).cloned().unwrap_or_default()
+    }
+
+    /// Returns the name of the plugin this access token belongs to
+    fn get_plugin_name(&self, access_token: &AccessToken) -> Option<String> {
+        let l = self.plugins.read().expect("Unable to read plugin list");
+        l.iter().find(|(_, plugin)| &plugin.access_token == access_token).map(|(name, _)| name.clone())
+    }
+
+    /// Runtime statistics of all plugins
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Time an interact call may take, before the called plugin is marked unhealthy<br>
+    /// Applies to Plugins handed out afterwards, returns false if it was already set
+    pub fn set_interact_deadline(&self, deadline: Duration) -> bool {
+        self.interact_deadline.set(deadline).is_ok()
+    }
+
+    /// A new clone of the plugin, that goes offline with it and counts the calls to it
+    fn hand_out(&self, plugin: &InteralPlugin) -> Plugin {
+        let hook = CallHook::new(plugin.library.clone(), self.stats.counters(&plugin.library), self.interact_deadline.get().copied());
+        plugin.plugin.renew(plugin.switchoff_handle.clone()).with_tracer(Arc::new(hook))
+    }
+
+    /// Counts the call for the plugin making it, and the error if it failed<br>
+    /// That is the plugin running on this thread, or the owner of the access token
+    /// Neither locks nor allocates, unless the call failed
+    fn account<T>(&self, op: Op, access_token: Option<&AccessToken>, what: impl Fn() -> String, call: impl FnOnce() -> Result<T, ()>) -> Result<T, ()> {
+        let result = call();
+
+        let count = |counters: &Counters| {
+            counters.count(op);
+            if result.is_err() {
+                counters.error(format!("{} failed", what()));
+            }
+        };
+        if stats::with_current(count).is_none() {
+            if let Some(token) = access_token {
+                if let Some(counters) = self.token_counters.load().get(token) {
+                    count(counters);
+                }
+            }
+        }
+        result
+    }
+
+    /// Key of the value for the trace, the handle might be outdated
+    fn key_of(&self, handle: &DataHandle) -> String {
+        self.data_store.get(handle.index).map(|cont| cont.name.load().to_string()).unwrap_or_default()
+    }
+
+    /// Returns the name and version of the plugin this access token belongs to
+    fn get_plugin_identity(&self, access_token: &AccessToken) -> Option<(String, String, String)> {
+        let l = self.plugins.read().expect("Unable to read plugin list");
+        l.values().find(|plugin| &plugin.access_token == access_token).map(|plugin| (plugin.plugin.name.clone(), plugin.library.clone(), plugin.plugin.version.clone()))
+    }
+
+    /// Returns the container for a handle, or Err if the handle is outdated
+    fn get_container(&self, handle: &DataHandle) -> Result<&DataContainer, ()> {
+        let cont = self.data_store.get(handle.index).ok_or(())?;
+        if cont.removed.load(Ordering::Acquire) || cont.name_hash.load(Ordering::Acquire) != handle.name_hash {
+            return Err(()); // Name was updated or removed, so the handle is outdated
+        }
+
+        Ok(cont)
+    }
+
+    /// Same as get_container, but also checks that the token is the owner of the value<br>
+    /// Mirrored values are owned by nobody here
+    fn get_owned_container(&self, handle: &DataHandle, access_token: &AccessToken) -> Result<&DataContainer, ()> {
+        let cont = self.get_container(handle)?;
+        if cont.mirrored || (&cont.owner != access_token && access_token != &self.loader_token) {
+            return Err(());
+            // This is not the owner, therefore does not have write permission
+        }
+
+        Ok(cont)
+    }
+
+    /// Same as get_owned_container, but also checks that the value is not read only
+    fn get_writable_container(&self, handle: &DataHandle, access_token: &AccessToken) -> Result<&DataContainer, ()> {
+        let cont = self.get_owned_container(handle, access_token)?;
+        if cont.derived.is_some() || (cont.metadata.read_only_after_init && !self.initializing.load(Ordering::Acquire)) {
+            return Err(());
+        }
+
+        Ok(cont)
+    }
+}
+
+impl Default for Data {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct InteralPlugin {
+    plugin: Plugin,
+    /// The stats of the plugin are counted under this name
+    library: String,
+    access_token: AccessToken,
+    switchoff_handle: Arc<AtomicBool>
+}
+
+#[derive(Clone)]
+struct Subscriber {
+    id: u64,
+    sender: SyncSender<JournalEntry>,
+    /// Set instead of blocking when the receiver is full
+    lagged: Arc<AtomicBool>
+}
+
+impl Subscriber {
+    /// Returns false if the receiver is gone
+    fn send(&self, entry: JournalEntry) -> bool {
+        match self.sender.try_send(entry) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.lagged.store(true, Ordering::Release);
+                true
+            },
+            Err(TrySendError::Disconnected(_)) => false
+        }
+    }
+}
+
+/// Changes of a subscription, dropping it ends the subscription
+pub struct Changes {
+    receiver: Receiver<JournalEntry>,
+    lagged: Arc<AtomicBool>
+}
+
+impl Changes {
+    pub fn try_recv(&self) -> Result<JournalEntry, TryRecvError> {
+        self.receiver.try_recv()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<JournalEntry, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+
+    /// Returns if changes were dropped since the last call, because the receiver was full
+    pub fn lagged(&self) -> bool {
+        self.lagged.swap(false, Ordering::AcqRel)
+    }
+}
+
+/// Total time spent waiting for a lock, and how often it was taken
+#[derive(Default)]
+pub struct LockWait {
+    waited_nanos: AtomicU64,
+    acquired: AtomicU64
+}
+
+impl LockWait {
+    fn time<T>(&self, lock: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let guard = lock();
+        self.waited_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        guard
+    }
+
+    pub fn waited(&self) -> Duration {
+        Duration::from_nanos(self.waited_nanos.load(Ordering::Relaxed))
+    }
+
+    pub fn acquired(&self) -> u64 {
+        self.acquired.load(Ordering::Relaxed)
+    }
+}
+
+/// Who a new value belongs to
+struct Owner {
+    token: AccessToken,
+    name: String,
+    mirrored: bool
+}
+
+struct DataContainer {
+    name: ArcSwap<String>,
+    owner: AccessToken,
+    owner_name: String,
+    /// Mirrored from a peer loader, only replication can change it
+    mirrored: bool,
+    name_hash: AtomicU64,
+    removed: AtomicBool,
+    metadata: Metadata,
+    value: ValueStore,
+    /// Only present if the metadata asks for a history
+    history: Option<Mutex<VecDeque<HistorySample>>>,
+    /// Only present for values computed from others
+    derived: Option<Derived>,
+    /// Indices of the derived values that use this value
+    dependents: ArcSwap<Vec<usize>>
+}
+
+struct Derived {
+    expr: Expr,
+    recompute_lock: Mutex<()>
+}
+
+impl DataContainer {
+    /// Runs a write on the value, if changed returns true for the result it is recorded into the history (if there is one)<br>
+    /// Returns the result of the write, and if it changed
+    fn write_if<R>(&self, f: impl FnOnce(&ValueStore) -> Result<R, ()>, changed: impl FnOnce(&R) -> bool) -> Result<(R, bool), ()> {
+        let (Some(history), Some(limit)) = (&self.history, self.metadata.history) else {
+            let res = f(&self.value)?;
+            let did_change = changed(&res);
+            return Ok((res, did_change));
+        };
+
+        // Holding the lock during the write, so the samples are in the same order as the writes
+        let mut history = history.lock().unwrap_or_else(|e| e.into_inner());
+        let res = f(&self.value)?;
+        if !changed(&res) {
+            return Ok((res, false));
+        }
+
+        let time = SystemTime::now();
+        history.push_back(HistorySample { time, value: self.value.read() });
+        trim_history(&mut history, limit, time);
+
+        Ok((res, true))
+    }
+
+    /// Converts the value into the stored type and applies the range from the metadata
+    fn constrain(&self, val: Value) -> Result<Value, ()> {
+        apply_range(&self.metadata, self.value.coerce(val)?)
+    }
+}
+
+/// Checks Int and Float values against min/max of the metadata, clamping or rejecting them according to the RangePolicy<br>
+/// NaN is outside of every range, so it is rejected (even with Clamp) if min or max is set
+fn apply_range(metadata: &Metadata, val: Value) -> Result<Value, ()> {
+    if metadata.min.is_none() && metadata.max.is_none() {
+        return Ok(val);
+    }
+    let num = match val {
+        Value::Int(i) => i as f64,
+        Value::Float(f) if f.is_nan() => return Err(()),
+        Value::Float(f) => f,
+        _ => return Ok(val)
+    };
+
+    let clamped = num.max(metadata.min.unwrap_or(f64::NEG_INFINITY)).min(metadata.max.unwrap_or(f64::INFINITY));
+    if clamped == num {
+        return Ok(val);
+    }
+
+    match (metadata.range_policy, val) {
+        (RangePolicy::Reject, _) => Err(()),
+        (RangePolicy::Clamp, Value::Int(_)) => {
+            // To the nearest whole number in range, there might be none
+            let min = metadata.min.map_or(f64::NEG_INFINITY, f64::ceil);
+            let max = metadata.max.map_or(f64::INFINITY, f64::floor);
+            if min > max {
+                return Err(());
+            }
+            Ok(Value::Int(num.max(min).min(max) as i64))
+        },
+        (RangePolicy::Clamp, _) => Ok(Value::Float(clamped))
+    }
+}
+
+/// Writer side of the batch_seq lock: odd while it lives, even again once dropped (even if the batch panicked)
+struct Batch<'a> {
+    seq: &'a AtomicU64,
+    start: u64
+}
+
+impl<'a> Batch<'a> {
+    /// Has to be called with batch_lock held
+    fn begin(seq: &'a AtomicU64) -> Batch<'a> {
+        let start = seq.load(Ordering::Relaxed);
+        seq.store(start + 1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+        Batch { seq, start }
+    }
+}
+
+impl Drop for Batch<'_> {
+    fn drop(&mut self) {
+        self.seq.store(self.start + 2, Ordering::Release);
+    }
+}
+
+/// Hash of the name stored in handles, so we notice when the name changed
+fn hash_name(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A new token, that can't be guessed from the name of the plugin
+fn random_token() -> AccessToken {
+    AccessToken::new(rand::thread_rng().sample_iter(Alphanumeric).take(TOKEN_LENGTH).map(char::from).collect())
+}
+
+/// Drops the samples beyond the limit, or older than it at now
+fn trim_history(history: &mut VecDeque<HistorySample>, limit: HistoryLimit, now: SystemTime) {
+    match limit {
+        HistoryLimit::Samples(n) => while history.len() > n {
+            history.pop_front();
+        },
+        HistoryLimit::Duration(duration) => while history.front().is_some_and(|sample| sample.time + duration < now) {
+            history.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(min: Option<f64>, max: Option<f64>, range_policy: RangePolicy) -> Metadata {
+        Metadata { min, max, range_policy, ..Metadata::default() }
+    }
+
+    fn with_plugin() -> (Data, AccessToken) {
+        let data = Data::new();
+        let token = data.register_plugin(Plugin::new("test".to_string(), "0.1.0".to_string(), None)).expect("Plugin should register");
+        (data, token)
+    }
+
+    #[test]
+    fn history_drops_expired_samples_on_read() {
+        let (data, token) = with_plugin();
+        let metadata = Metadata { history: Some(HistoryLimit::Duration(Duration::from_millis(50))), ..Metadata::default() };
+        let handle = data.create_value_with_metadata("Temp".to_string(), &token, Value::Int(1), metadata).unwrap();
+        data.set_value(&handle, &token, Value::Int(2)).unwrap();
+        assert!(!data.get_history(&handle, HistoryRange::All).unwrap().is_empty());
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(data.get_history(&handle, HistoryRange::All), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn derived_value_follows_renamed_input() {
+        let (data, token) = with_plugin();
+        let speed = data.create_value("Speed".to_string(), &token, Value::Float(10.0)).unwrap();
+        let kmh = data.create_derived_value("Kmh".to_string(), &token, "Speed * 3.6", Metadata::default()).unwrap();
+
+        let speed = data.rename_value(&speed, &token, "Velocity".to_string()).unwrap();
+        data.set_value(&speed, &token, Value::Float(20.0)).unwrap();
+        assert_eq!(data.get_value(&kmh), Ok(Value::Float(72.0)));
+    }
+
+    #[test]
+    fn derived_value_is_deleted_with_its_input() {
+        let (data, token) = with_plugin();
+        let speed = data.create_value("Speed".to_string(), &token, Value::Float(10.0)).unwrap();
+        let kmh = data.create_derived_value("Kmh".to_string(), &token, "Speed * 3.6", Metadata::default()).unwrap();
+        let mph = data.create_derived_value("Mph".to_string(), &token, "Kmh / 1.609", Metadata::default()).unwrap();
+
+        data.delete_value(&speed, &token).unwrap();
+        assert_eq!(data.get_value(&kmh), Err(()));
+        assert_eq!(data.get_value(&mph), Err(()));
+        assert!(data.get_data_handle("Kmh").is_none());
+
+        // A new value under the old key doesn't bring them back, they can be created again
+        data.create_value("Speed".to_string(), &token, Value::Float(1.0)).unwrap();
+        assert!(data.create_derived_value("Kmh".to_string(), &token, "Speed * 3.6", Metadata::default()).is_ok());
+    }
+
+    #[test]
+    fn apply_range_without_range_accepts_everything() {
+        let metadata = Metadata::default();
+        assert!(matches!(apply_range(&metadata, Value::Float(f64::NAN)), Ok(Value::Float(f)) if f.is_nan()));
+        assert_eq!(apply_range(&metadata, Value::Float(f64::INFINITY)), Ok(Value::Float(f64::INFINITY)));
+        assert_eq!(apply_range(&metadata, Value::Int(i64::MAX)), Ok(Value::Int(i64::MAX)));
+    }
+
+    #[test]
+    fn apply_range_rejects_nan_with_a_range() {
+        assert_eq!(apply_range(&range(Some(0.0), None, RangePolicy::Reject), Value::Float(f64::NAN)), Err(()));
+        assert_eq!(apply_range(&range(None, Some(1.0), RangePolicy::Clamp), Value::Float(f64::NAN)), Err(()));
+    }
+
+    #[test]
+    fn apply_range_clamps_or_rejects() {
+        let reject = range(Some(0.0), Some(10.0), RangePolicy::Reject);
+        assert_eq!(apply_range(&reject, Value::Float(5.0)), Ok(Value::Float(5.0)));
+        assert_eq!(apply_range(&reject, Value::Float(11.0)), Err(()));
+        assert_eq!(apply_range(&reject, Value::Int(-1)), Err(()));
+
+        let clamp = range(Some(0.0), Some(10.0), RangePolicy::Clamp);
+        assert_eq!(apply_range(&clamp, Value::Float(f64::INFINITY)), Ok(Value::Float(10.0)));
+        assert_eq!(apply_range(&clamp, Value::Int(-5)), Ok(Value::Int(0)));
+        assert_eq!(apply_range(&clamp, Value::Str("text".to_string())), Ok(Value::Str("text".to_string())));
+    }
+
+    #[test]
+    fn apply_range_clamps_ints_into_fractional_bounds() {
+        let clamp = range(Some(0.5), Some(2.5), RangePolicy::Clamp);
+        assert_eq!(apply_range(&clamp, Value::Int(0)), Ok(Value::Int(1)));
+        assert_eq!(apply_range(&clamp, Value::Int(3)), Ok(Value::Int(2)));
+        assert_eq!(apply_range(&clamp, Value::Int(2)), Ok(Value::Int(2)));
+        assert_eq!(apply_range(&clamp, Value::Float(0.0)), Ok(Value::Float(0.5)));
+
+        let no_int = range(Some(0.2), Some(0.8), RangePolicy::Clamp);
+        assert_eq!(apply_range(&no_int, Value::Int(0)), Err(()));
+        assert_eq!(apply_range(&range(Some(-1.5), None, RangePolicy::Clamp), Value::Int(-5)), Ok(Value::Int(-1)));
+    }
+}
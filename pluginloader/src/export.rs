@@ -0,0 +1,95 @@
+//! Dumping the whole Datastore into JSON or TOML, and preloading values from such a file
+
+use std::{fs, io, path::Path};
+
+use plugin_sdk::{Datastore, KeyPattern, Metadata, Value, ValueType};
+use serde::{Serialize, Deserialize};
+
+use crate::datastore::Data;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoreDump {
+    #[serde(default)]
+    pub values: Vec<DumpedValue>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DumpedValue {
+    pub key: String,
+    /// Informational, preloaded values are always owned by the loader
+    #[serde(default)]
+    pub owner: String,
+    /// Informational, the type is taken from the value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_type: Option<ValueType>,
+    pub value: Value,
+    #[serde(default)]
+    pub metadata: Metadata
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml
+}
+
+impl Format {
+    /// Picks the format from the file extension, defaulting to json
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => Format::Toml,
+            _ => Format::Json
+        }
+    }
+}
+
+/// Collects all values in the store, sorted by key
+pub fn dump(data: &dyn Datastore) -> StoreDump {
+    let values = data.list_keys(&KeyPattern::All).into_iter().filter_map(|info| {
+        Some(DumpedValue {
+            value: data.get_value(&info.handle).ok()?,
+            metadata: data.get_metadata(&info.handle).ok()?,
+            key: info.key,
+            owner: info.owner,
+            value_type: Some(info.value_type)
+        })
+    }).collect();
+
+    StoreDump { values }
+}
+
+pub fn to_string(dump: &StoreDump, format: Format) -> io::Result<String> {
+    match format {
+        Format::Json => Ok(serde_json::to_string_pretty(dump)?),
+        Format::Toml => toml::to_string_pretty(dump).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+pub fn from_str(text: &str, format: Format) -> io::Result<StoreDump> {
+    match format {
+        Format::Json => Ok(serde_json::from_str(text)?),
+        Format::Toml => toml::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Writes the whole store into the file, the format is picked from the extension
+pub fn export(data: &dyn Datastore, path: &Path) -> io::Result<()> {
+    fs::write(path, to_string(&dump(data), Format::from_path(path))?)
+}
+
+/// Creates all values from the file under the loader as the owner<br>
+/// Values that can not be created (like the key already existing) are skipped with a message.<br>
+/// Returns the number of values that were created
+pub fn preload(data: &Data, path: &Path) -> io::Result<usize> {
+    let dump = from_str(&fs::read_to_string(path)?, Format::from_path(path))?;
+
+    let mut created = 0;
+    for val in dump.values {
+        match data.create_value_with_metadata(val.key.clone(), data.loader_token(), val.value, val.metadata) {
+            Ok(_) => created += 1,
+            Err(()) => log::warn!("Unable to preload {}", val.key)
+        }
+    }
+
+    Ok(created)
+}
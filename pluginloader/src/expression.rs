@@ -0,0 +1,512 @@
+//! Small expression language for derived values<br>
+//! <br>
+//! Supports Int, Float, Bool and Str literals, keys of other values, the operators<br>
+//! `+ - * / % == != < <= > >= && || !`, parentheses and the functions `abs(a)`, `min(a, b)`, `max(a, b)` and `if(cond, a, b)`.<br>
+//! Keys are written as is (`Speed * 3.6`), or in curly braces if they contain other characters (`{Lap Time} / 60`).<br>
+//! Parsing and evaluating recurse, so expressions are limited in length and nesting to keep the stack safe.
+
+use std::cmp::Ordering;
+
+use plugin_sdk::{DataHandle, Value};
+
+/// Most tokens an expression can have
+const MAX_TOKENS: usize = 1024;
+/// Deepest nesting of parentheses, function calls and unary operators
+const MAX_DEPTH: usize = 64;
+
+/// A parsed expression, with all keys already resolved into handles
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Literal(Value),
+    Input(DataHandle),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum UnaryOp {
+    Neg,
+    Not
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Func {
+    Abs,
+    Min,
+    Max,
+    If
+}
+
+impl Func {
+    fn from_name(name: &str) -> Option<(Func, usize)> {
+        match name {
+            "abs" => Some((Func::Abs, 1)),
+            "min" => Some((Func::Min, 2)),
+            "max" => Some((Func::Max, 2)),
+            "if" => Some((Func::If, 3)),
+            _ => None
+        }
+    }
+}
+
+impl Expr {
+    /// Parses the expression, resolve is called for every key to turn it into a handle
+    pub fn parse(src: &str, resolve: impl Fn(&str) -> Option<DataHandle>) -> Result<Expr, String> {
+        let tokens = tokenize(src)?;
+        if tokens.len() > MAX_TOKENS {
+            return Err(format!("The expression is too long, at most {} tokens are allowed", MAX_TOKENS));
+        }
+        let mut parser = Parser { tokens, pos: 0, depth: 0, resolve: &resolve };
+
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("Unexpected {:?} after the end of the expression", token));
+        }
+        Ok(expr)
+    }
+
+    /// All handles this expression reads from
+    pub fn inputs(&self) -> Vec<DataHandle> {
+        let mut inputs = vec![];
+        self.collect_inputs(&mut inputs);
+        inputs
+    }
+
+    fn collect_inputs(&self, inputs: &mut Vec<DataHandle>) {
+        match self {
+            Expr::Literal(_) => (),
+            Expr::Input(handle) => if !inputs.contains(handle) {
+                inputs.push(handle.clone());
+            },
+            Expr::Unary(_, expr) => expr.collect_inputs(inputs),
+            Expr::Binary(_, left, right) => {
+                left.collect_inputs(inputs);
+                right.collect_inputs(inputs);
+            },
+            Expr::Call(_, args) => args.iter().for_each(|arg| arg.collect_inputs(inputs))
+        }
+    }
+
+    /// Evaluates the expression, reading inputs with get
+    pub fn eval(&self, get: &impl Fn(&DataHandle) -> Result<Value, ()>) -> Result<Value, ()> {
+        match self {
+            Expr::Literal(val) => Ok(val.clone()),
+            Expr::Input(handle) => get(handle),
+            Expr::Unary(UnaryOp::Neg, expr) => match expr.eval(get)? {
+                Value::Int(i) => Ok(Value::Int(i.wrapping_neg())),
+                Value::Bool(b) => Ok(Value::Int(-(b as i64))),
+                Value::Float(f) => Ok(Value::Float(-f)),
+                Value::Str(_) => Err(())
+            },
+            Expr::Unary(UnaryOp::Not, expr) => Ok(Value::Bool(!truthy(expr.eval(get)?)?)),
+            Expr::Binary(BinaryOp::And, left, right) => Ok(Value::Bool(truthy(left.eval(get)?)? && truthy(right.eval(get)?)?)),
+            Expr::Binary(BinaryOp::Or, left, right) => Ok(Value::Bool(truthy(left.eval(get)?)? || truthy(right.eval(get)?)?)),
+            Expr::Binary(op, left, right) => binary(*op, left.eval(get)?, right.eval(get)?),
+            Expr::Call(Func::If, args) => if truthy(args[0].eval(get)?)? {
+                args[1].eval(get)
+            } else {
+                args[2].eval(get)
+            },
+            Expr::Call(Func::Abs, args) => match args[0].eval(get)? {
+                Value::Int(i) => Ok(Value::Int(i.wrapping_abs())),
+                Value::Float(f) => Ok(Value::Float(f.abs())),
+                val @ Value::Bool(_) => Ok(val),
+                Value::Str(_) => Err(())
+            },
+            Expr::Call(func, args) => {
+                let (a, b) = (args[0].eval(get)?, args[1].eval(get)?);
+                let ord = compare(&a, &b)?;
+                Ok(match (func, ord) {
+                    (Func::Min, Ordering::Greater) | (Func::Max, Ordering::Less) => b,
+                    _ => a
+                })
+            }
+        }
+    }
+}
+
+fn truthy(val: Value) -> Result<bool, ()> {
+    val.try_into().map_err(|_| ())
+}
+
+/// Numbers as they are used in arithmetic, Bools count as Int
+enum Num {
+    Int(i64),
+    Float(f64)
+}
+
+fn num(val: &Value) -> Result<Num, ()> {
+    match val {
+        Value::Int(i) => Ok(Num::Int(*i)),
+        Value::Bool(b) => Ok(Num::Int(*b as i64)),
+        Value::Float(f) => Ok(Num::Float(*f)),
+        Value::Str(_) => Err(())
+    }
+}
+
+fn as_float(num: Num) -> f64 {
+    match num {
+        Num::Int(i) => i as f64,
+        Num::Float(f) => f
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Result<Ordering, ()> {
+    match (a, b) {
+        (Value::Str(a), Value::Str(b)) => Ok(a.cmp(b)),
+        (Value::Str(_), _) | (_, Value::Str(_)) => Err(()),
+        _ => match (num(a)?, num(b)?) {
+            (Num::Int(a), Num::Int(b)) => Ok(a.cmp(&b)),
+            (a, b) => as_float(a).partial_cmp(&as_float(b)).ok_or(())
+        }
+    }
+}
+
+fn binary(op: BinaryOp, a: Value, b: Value) -> Result<Value, ()> {
+    match op {
+        BinaryOp::Eq => return Ok(Value::Bool(compare(&a, &b)? == Ordering::Equal)),
+        BinaryOp::Ne => return Ok(Value::Bool(compare(&a, &b)? != Ordering::Equal)),
+        BinaryOp::Lt => return Ok(Value::Bool(compare(&a, &b)? == Ordering::Less)),
+        BinaryOp::Le => return Ok(Value::Bool(compare(&a, &b)? != Ordering::Greater)),
+        BinaryOp::Gt => return Ok(Value::Bool(compare(&a, &b)? == Ordering::Greater)),
+        BinaryOp::Ge => return Ok(Value::Bool(compare(&a, &b)? != Ordering::Less)),
+        _ => ()
+    }
+
+    if let (BinaryOp::Add, Value::Str(_), _) | (BinaryOp::Add, _, Value::Str(_)) = (op, &a, &b) {
+        return Ok(Value::Str(a.to_string() + &b.to_string()));
+    }
+
+    match (num(&a)?, num(&b)?) {
+        (Num::Int(a), Num::Int(b)) => match op {
+            BinaryOp::Add => Ok(Value::Int(a.wrapping_add(b))),
+            BinaryOp::Sub => Ok(Value::Int(a.wrapping_sub(b))),
+            BinaryOp::Mul => Ok(Value::Int(a.wrapping_mul(b))),
+            BinaryOp::Div => a.checked_div(b).map(Value::Int).ok_or(()),
+            BinaryOp::Rem => a.checked_rem(b).map(Value::Int).ok_or(()),
+            _ => Err(())
+        },
+        (a, b) => {
+            let (a, b) = (as_float(a), as_float(b));
+            match op {
+                BinaryOp::Add => Ok(Value::Float(a + b)),
+                BinaryOp::Sub => Ok(Value::Float(a - b)),
+                BinaryOp::Mul => Ok(Value::Float(a * b)),
+                BinaryOp::Div => Ok(Value::Float(a / b)),
+                BinaryOp::Rem => Ok(Value::Float(a % b)),
+                _ => Err(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Ident(String),
+    Key(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma
+}
+
+const OPERATORS: [&str; 16] = ["==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!", "(", ")"];
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            tokens.push(if text.contains('.') {
+                Token::Float(text.parse().map_err(|_| format!("Invalid number {}", text))?)
+            } else {
+                Token::Int(text.parse().map_err(|_| format!("Invalid number {}", text))?)
+            });
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '"' || c == '{' {
+            let end = if c == '"' { '"' } else { '}' };
+            let start = i + 1;
+            i = start;
+            while i < chars.len() && chars[i] != end {
+                i += 1;
+            }
+            if i == chars.len() {
+                return Err(format!("Missing closing {}", end));
+            }
+            let text: String = chars[start..i].iter().collect();
+            tokens.push(if c == '"' { Token::Str(text) } else { Token::Key(text) });
+            i += 1;
+        } else if c == ',' {
+            tokens.push(Token::Comma);
+            i += 1;
+        } else if let Some(op) = OPERATORS.iter().find(|op| src_starts_with(&chars[i..], op)) {
+            tokens.push(match *op {
+                "(" => Token::LParen,
+                ")" => Token::RParen,
+                op => Token::Op(op)
+            });
+            i += op.len();
+        } else {
+            return Err(format!("Unexpected character {}", c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn src_starts_with(chars: &[char], op: &str) -> bool {
+    op.chars().enumerate().all(|(i, c)| chars.get(i) == Some(&c))
+}
+
+struct Parser<'a, F: Fn(&str) -> Option<DataHandle>> {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+    resolve: &'a F
+}
+
+impl<F: Fn(&str) -> Option<DataHandle>> Parser<'_, F> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Consumes the next token if it is one of the operators
+    fn take_op(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        if let Some(Token::Op(op)) = self.tokens.get(self.pos) {
+            if ops.contains(op) {
+                self.pos += 1;
+                return Some(op);
+            }
+        }
+        None
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), String> {
+        match self.next() {
+            Some(t) if t == token => Ok(()),
+            Some(t) => Err(format!("Expected {:?}, found {:?}", token, t)),
+            None => Err(format!("Expected {:?}, found the end of the expression", token))
+        }
+    }
+
+    /// Parses one level deeper, failing once MAX_DEPTH is reached
+    fn nested(&mut self, parse: impl FnOnce(&mut Self) -> Result<Expr, String>) -> Result<Expr, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(format!("The expression is nested too deep, at most {} levels are allowed", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut left = self.and()?;
+        while self.take_op(&["||"]).is_some() {
+            left = Expr::Binary(BinaryOp::Or, Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut left = self.comparison()?;
+        while self.take_op(&["&&"]).is_some() {
+            left = Expr::Binary(BinaryOp::And, Box::new(left), Box::new(self.comparison()?));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.sum()?;
+        let op = match self.take_op(&["==", "!=", "<=", ">=", "<", ">"]) {
+            Some("==") => BinaryOp::Eq,
+            Some("!=") => BinaryOp::Ne,
+            Some("<=") => BinaryOp::Le,
+            Some(">=") => BinaryOp::Ge,
+            Some("<") => BinaryOp::Lt,
+            Some(">") => BinaryOp::Gt,
+            _ => return Ok(left)
+        };
+        Ok(Expr::Binary(op, Box::new(left), Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut left = self.product()?;
+        while let Some(op) = self.take_op(&["+", "-"]) {
+            let op = if op == "+" { BinaryOp::Add } else { BinaryOp::Sub };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.product()?));
+        }
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.take_op(&["*", "/", "%"]) {
+            let op = match op {
+                "*" => BinaryOp::Mul,
+                "/" => BinaryOp::Div,
+                _ => BinaryOp::Rem
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.take_op(&["-", "!"]) {
+            Some("-") => Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.nested(Self::unary)?))),
+            Some(_) => Ok(Expr::Unary(UnaryOp::Not, Box::new(self.nested(Self::unary)?))),
+            None => self.atom()
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Int(i)) => Ok(Expr::Literal(Value::Int(i))),
+            Some(Token::Float(f)) => Ok(Expr::Literal(Value::Float(f))),
+            Some(Token::Str(str)) => Ok(Expr::Literal(Value::Str(str))),
+            Some(Token::LParen) => {
+                let expr = self.nested(Self::or)?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            },
+            Some(Token::Ident(name)) if self.tokens.get(self.pos) == Some(&Token::LParen) => {
+                let (func, arg_count) = Func::from_name(&name).ok_or_else(|| format!("Unknown function {}", name))?;
+                self.pos += 1;
+
+                self.nested(|parser| {
+                    let mut args = vec![parser.or()?];
+                    while args.len() < arg_count {
+                        parser.expect(Token::Comma)?;
+                        args.push(parser.or()?);
+                    }
+                    parser.expect(Token::RParen)?;
+                    Ok(Expr::Call(func, args))
+                })
+            },
+            Some(Token::Ident(name)) if name == "true" => Ok(Expr::Literal(Value::Bool(true))),
+            Some(Token::Ident(name)) if name == "false" => Ok(Expr::Literal(Value::Bool(false))),
+            Some(Token::Ident(key)) | Some(Token::Key(key)) => {
+                (self.resolve)(&key).map(Expr::Input).ok_or_else(|| format!("Unknown key {}", key))
+            },
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+            None => Err("Unexpected end of the expression".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keys a to d are inputs 0 to 3
+    fn parse(src: &str) -> Result<Expr, String> {
+        Expr::parse(src, |key| ["a", "b", "c", "d"].iter().position(|k| *k == key).map(|index| DataHandle { index, name_hash: 0 }))
+    }
+
+    fn eval(src: &str) -> Result<Value, ()> {
+        let inputs = [Value::Int(3), Value::Float(1.5), Value::Bool(true), Value::Str("x".to_string())];
+        parse(src).expect("Expression should parse").eval(&|handle: &DataHandle| Ok(inputs[handle.index].clone()))
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(Value::Int(7)));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(Value::Int(9)));
+        assert_eq!(eval("-2 * -a"), Ok(Value::Int(6)));
+        assert_eq!(eval("1 < 2 && 2 < 1 || true"), Ok(Value::Bool(true)));
+        assert_eq!(eval("!(a == 3)"), Ok(Value::Bool(false)));
+    }
+
+    #[test]
+    fn inputs_and_types() {
+        assert_eq!(eval("a * b"), Ok(Value::Float(4.5)));
+        assert_eq!(eval("a + c"), Ok(Value::Int(4)));
+        assert_eq!(eval("d + a"), Ok(Value::Str("x3".to_string())));
+        assert_eq!(eval("7 / 2"), Ok(Value::Int(3)));
+        assert_eq!(eval("7.0 / 2"), Ok(Value::Float(3.5)));
+        assert_eq!(parse("a + b * a").unwrap().inputs().len(), 2);
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(eval("abs(-a)"), Ok(Value::Int(3)));
+        assert_eq!(eval("min(a, b)"), Ok(Value::Float(1.5)));
+        assert_eq!(eval("max(a, b)"), Ok(Value::Int(3)));
+        assert_eq!(eval("if(a > 2, \"big\", \"small\")"), Ok(Value::Str("big".to_string())));
+    }
+
+    #[test]
+    fn evaluation_errors() {
+        assert_eq!(eval("a / 0"), Err(()));
+        assert_eq!(eval("a % 0"), Err(()));
+        assert_eq!(eval("-d"), Err(()));
+        assert_eq!(eval("d < a"), Err(()));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse("a +").is_err());
+        assert!(parse("(a").is_err());
+        assert!(parse("a b").is_err());
+        assert!(parse("unknown").is_err());
+        assert!(parse("sqrt(a)").is_err());
+        assert!(parse("min(a)").is_err());
+        assert!(parse("{a").is_err());
+        assert!(parse("a $ b").is_err());
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(parse(&format!("{}a", "-".repeat(MAX_DEPTH + 1))).is_err());
+        // Would overflow the stack without the limits
+        assert!(parse(&nested(100_000)).is_err());
+    }
+
+    #[test]
+    fn length_is_limited() {
+        assert!(parse(&vec!["a"; MAX_TOKENS / 2].join("+")).is_ok());
+        assert!(parse(&vec!["a"; MAX_TOKENS].join("+")).is_err());
+    }
+}
@@ -0,0 +1,441 @@
+//! Loads the plugin libraries and drives them: init, the update loops, end and unloading again<br>
+//! Loading and unloading is serialized, so the plugins a library registers in init can be told apart.<br>
+//! Every call into a library is a trace span, so the Datastore operations and interact calls it makes show up inside.<br>
+//! The time spent in init, update and end goes into the stats of the library, as do failed inits and panics.<br>
+//! With deadlines set a call that takes too long is abandoned, the plugin is marked unhealthy and not called anymore.<br>
+//! Overdue updates are found by check_deadlines, their thread is left running without being waited for.
+
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, sync::{Arc, Mutex, RwLock, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+use dlopen2::wrapper::{WrapperApi, Container};
+use log::{info, warn, error};
+use plugin_sdk::{Datastore, PluginConfig};
+
+use crate::{config::WatchdogConfig, datastore::Data, stats::Phase, watchdog::{self, Missed}};
+
+/// How often wait checks if the update loops are done
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(WrapperApi)]
+struct PluginWrapper {
+    init: fn(storage: &'static dyn Datastore) -> Result<(), String>,
+    update: Option<fn(storage: &'static dyn Datastore)>,
+    config_changed: Option<fn(storage: &'static dyn Datastore, config: PluginConfig)>,
+    shutdown: Option<fn(storage: &'static dyn Datastore)>,
+    end: fn(storage: &'static dyn Datastore)
+}
+
+struct Loaded {
+    name: String,
+    path: PathBuf,
+    /// Shared with calls that were abandoned after their deadline, to keep the library loaded until they return
+    lib: Arc<Container<PluginWrapper>>,
+    /// Plugins that were registered during init of this library
+    registered: Vec<String>,
+    /// Some once start was called, with the interval between update calls (None for a single call)
+    schedule: Mutex<Option<Option<Duration>>>,
+    stop: AtomicBool,
+    update: Mutex<Option<JoinHandle<()>>>,
+    /// Duration of the last update call, in nanoseconds
+    last_update_nanos: AtomicU64,
+    /// When the running update call started
+    update_started: Mutex<Option<Instant>>
+}
+
+impl Loaded {
+    /// Calls update, measuring how long it took
+    fn timed_update(&self, data: &'static Data) {
+        let _span = tracing::info_span!("update", plugin = self.name).entered();
+        let start = Instant::now();
+        *self.update_started.lock().unwrap_or_else(|e| e.into_inner()) = Some(start);
+        data.stats().time(&self.name, Phase::Update, || self.lib.update(data));
+        *self.update_started.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        self.last_update_nanos.store(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn is_healthy(&self, data: &Data) -> bool {
+        data.stats().counters(&self.name).unhealthy().is_none()
+    }
+}
+
+/// What the host knows about a loaded library
+#[derive(Debug, Clone)]
+pub struct LoadedInfo {
+    /// Name of the library, as used in the config
+    pub name: String,
+    pub path: PathBuf,
+    /// Names the library registered its plugins under
+    pub registered: Vec<String>,
+    /// If an update call or loop is currently running
+    pub updating: bool,
+    /// Number of finished update calls
+    pub update_calls: u64,
+    /// Time spent in all update calls together
+    pub update_time: Duration,
+    /// Time the last update call took
+    pub last_update: Duration,
+    /// Why the library is not called anymore, like a missed deadline
+    pub unhealthy: Option<String>
+}
+
+pub struct Host {
+    data: &'static Data,
+    /// In the order the libraries were loaded
+    plugins: RwLock<Vec<Arc<Loaded>>>,
+    lifecycle: Mutex<()>,
+    failed: AtomicUsize,
+    deadlines: WatchdogConfig
+}
+
+impl Host {
+    /// The deadlines for init, update and end are taken from the watchdog config, interact is up to the Datastore
+    pub fn new(data: &'static Data, deadlines: WatchdogConfig) -> Host {
+        Host { data, plugins: RwLock::new(vec![]), lifecycle: Mutex::new(()), failed: AtomicUsize::new(0), deadlines }
+    }
+
+    /// Logs the missed deadline and marks the plugin unhealthy, failed counts it from then on
+    fn missed(&self, name: &str, call: &str, missed: Missed) {
+        error!("Plugin {} {} in {}, it is marked unhealthy and not called anymore", name, missed, call);
+        self.data.stats().counters(name).mark_unhealthy(format!("{} in {}", missed, call));
+    }
+
+    /// Loads the library and calls its init, failures are counted
+    pub fn load(&self, name: &str, path: &Path) -> Result<(), String> {
+        let _guard = self.lifecycle.lock().unwrap_or_else(|e| e.into_inner());
+        self.load_locked(name, path).inspect_err(|e| {
+            error!("Failed to load plugin {}: {}", name, e);
+            self.data.stats().counters(name).error(format!("failed to load: {}", e));
+            self.failed.fetch_add(1, Ordering::AcqRel);
+        })
+    }
+
+    fn load_locked(&self, name: &str, path: &Path) -> Result<(), String> {
+        if self.get(name).is_some() {
+            return Err(format!("{} is already loaded", name));
+        }
+        info!("Loading plugin {} from {}", name, path.display());
+
+        let lib = Arc::new(unsafe { Container::<PluginWrapper>::load(path.as_os_str()) }.map_err(|e| e.to_string())?);
+        // A new library gets a new chance, even if the last one under this name missed a deadline
+        self.data.stats().counters(name).set_healthy();
+
+        let before: Vec<String> = self.data.plugins().into_iter().map(|p| p.name).collect();
+        let (data, init_lib, init_name) = (self.data, lib.clone(), name.to_string());
+        let result = tracing::info_span!("init", plugin = name).in_scope(|| watchdog::call(self.deadlines.init(), move || {
+            data.stats().time(&init_name, Phase::Init, || init_lib.init(data))
+        }));
+        let registered: Vec<String> = self.data.plugins().into_iter().map(|p| p.name).filter(|n| !before.contains(n)).collect();
+        // Counted as failed by load. What it registered so far is purged, so it can be loaded again.
+        // An init that is still running can't register again, as its library is unhealthy now
+        let failed = match result {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e),
+            Err(missed) => {
+                self.data.stats().counters(name).mark_unhealthy(format!("{} in init", missed));
+                Some(format!("{} in init", missed))
+            }
+        };
+        if let Some(e) = failed {
+            for registered in &registered {
+                let deleted = self.data.purge_plugin(registered);
+                info!("Purged plugin {} after its failed init, deleted its {} values", registered, deleted);
+            }
+            return Err(e);
+        }
+
+        let loaded = Loaded { name: name.to_string(), path: path.to_path_buf(), lib, registered, schedule: Mutex::new(None), stop: AtomicBool::new(false), update: Mutex::new(None),
+            last_update_nanos: AtomicU64::new(0), update_started: Mutex::new(None) };
+        self.plugins.write().expect("Unable to write plugin list").push(Arc::new(loaded));
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Option<Arc<Loaded>> {
+        self.plugins.read().expect("Unable to read plugin list").iter().find(|p| p.name == name).cloned()
+    }
+
+    /// Starts calling update of the plugin, once or every interval until stopped
+    pub fn start(&self, name: &str, interval: Option<Duration>) {
+        if let Some(plugin) = self.get(name) {
+            self.start_loaded(plugin, interval);
+        }
+    }
+
+    fn start_loaded(&self, plugin: Arc<Loaded>, interval: Option<Duration>) {
+        *plugin.schedule.lock().unwrap_or_else(|e| e.into_inner()) = Some(interval);
+        if !plugin.lib.has_update() || !plugin.is_healthy(self.data) {
+            return;
+        }
+
+        let data = self.data;
+        let runner = plugin.clone();
+        let handle = thread::spawn(move || {
+            match interval {
+                None => runner.timed_update(data),
+                Some(interval) => while !runner.stop.load(Ordering::Acquire) {
+                    let start = Instant::now();
+                    runner.timed_update(data);
+                    if let Some(rest) = interval.checked_sub(start.elapsed()) {
+                        thread::sleep(rest);
+                    }
+                }
+            }
+        });
+        *plugin.update.lock().unwrap_or_else(|e| e.into_inner()) = Some(handle);
+    }
+
+    /// Ends all update loops after their current call
+    pub fn stop_all(&self) {
+        for plugin in self.plugins.read().expect("Unable to read plugin list").iter() {
+            plugin.stop.store(true, Ordering::Release);
+        }
+    }
+
+    /// Waits for the update thread of the plugin, counting a panic as failure<br>
+    /// Polls, so a running update call that gets overdue is abandoned instead of waited for forever
+    fn join(&self, plugin: &Loaded) {
+        loop {
+            let finished = plugin.update.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|h| h.is_finished());
+            match finished {
+                None => return,
+                Some(true) => break,
+                Some(false) if self.abandon_overdue(plugin) || self.detach_unhealthy(plugin) => return,
+                Some(false) => thread::sleep(WAIT_POLL_INTERVAL)
+            }
+        }
+
+        let handle = plugin.update.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(handle) = handle {
+            if handle.join().is_err() {
+                error!("Plugin {} panicked in update", plugin.name);
+                self.data.stats().counters(&plugin.name).error("panicked in update".to_string());
+                self.failed.fetch_add(1, Ordering::AcqRel);
+            }
+        }
+    }
+
+    /// If the running update call of the plugin is over the deadline, marks the plugin unhealthy and stops its update loop<br>
+    /// The thread is left running without waiting for it. Returns if it was abandoned
+    fn abandon_overdue(&self, plugin: &Loaded) -> bool {
+        let Some(deadline) = self.deadlines.update() else {
+            return false;
+        };
+
+        let overdue = plugin.update_started.lock().unwrap_or_else(|e| e.into_inner()).is_some_and(|start| start.elapsed() > deadline);
+        // Already unhealthy for another reason, so it is not recorded twice (detach_unhealthy takes care of it)
+        if !overdue || !plugin.is_healthy(self.data) {
+            return false;
+        }
+        plugin.stop.store(true, Ordering::Release);
+        // Dropping the handle detaches the thread
+        plugin.update.lock().unwrap_or_else(|e| e.into_inner()).take();
+        self.missed(&plugin.name, "update", Missed::Overdue(deadline));
+        true
+    }
+
+    /// Stops the update loop of an unhealthy plugin and leaves its running call behind, whatever made it unhealthy<br>
+    /// Returns if it was detached
+    fn detach_unhealthy(&self, plugin: &Loaded) -> bool {
+        if plugin.is_healthy(self.data) {
+            return false;
+        }
+        plugin.stop.store(true, Ordering::Release);
+        plugin.update.lock().unwrap_or_else(|e| e.into_inner()).take().is_some()
+    }
+
+    /// Abandons the update calls running longer than the deadline, see abandon_overdue<br>
+    /// Called by wait, and periodically by the watchdog thread
+    pub fn check_deadlines(&self) {
+        let plugins = self.plugins.read().expect("Unable to read plugin list").clone();
+        for plugin in plugins {
+            self.abandon_overdue(&plugin);
+        }
+    }
+
+    /// Checks the update deadlines until the program ends, if there is one
+    pub fn spawn_watchdog(&'static self) {
+        if self.deadlines.update().is_some() {
+            thread::spawn(move || loop {
+                thread::sleep(WAIT_POLL_INTERVAL);
+                self.check_deadlines();
+            });
+        }
+    }
+
+    /// Blocks until no update is running anymore<br>
+    /// Polls instead of joining, so plugins can be unloaded and reloaded in the meantime
+    pub fn wait(&self) {
+        loop {
+            self.check_deadlines();
+            let plugins = self.plugins.read().expect("Unable to read plugin list").clone();
+            let mut running = false;
+
+            for plugin in plugins {
+                let finished = plugin.update.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|h| h.is_finished());
+                match finished {
+                    Some(true) => self.join(&plugin),
+                    Some(false) if self.detach_unhealthy(&plugin) => warn!("Not waiting for the update of plugin {}, it is unhealthy", plugin.name),
+                    Some(false) => running = true,
+                    None => ()
+                }
+            }
+
+            if !running {
+                return;
+            }
+            thread::sleep(WAIT_POLL_INTERVAL);
+        }
+    }
+
+    /// Calls end of the plugin, unless it is unhealthy
+    fn end(&self, plugin: &Arc<Loaded>) {
+        if let Some(reason) = self.data.stats().counters(&plugin.name).unhealthy() {
+            warn!("Not ending plugin {}, it is unhealthy: {}", plugin.name, reason);
+            return;
+        }
+
+        let (data, runner) = (self.data, plugin.clone());
+        let result = tracing::info_span!("end", plugin = plugin.name).in_scope(|| watchdog::call(self.deadlines.end(), move || {
+            data.stats().time(&runner.name, Phase::End, || runner.lib.end(data))
+        }));
+        if let Err(missed) = result {
+            self.missed(&plugin.name, "end", missed);
+        }
+    }
+
+    /// Tells the plugins that export shutdown that the loader is shutting down, in the order end is called<br>
+    /// Their update calls might still be running and can return early. The end deadline applies
+    pub fn notify_shutdown(&self) {
+        let _guard = self.lifecycle.lock().unwrap_or_else(|e| e.into_inner());
+        for plugin in self.end_order().iter().filter(|plugin| plugin.lib.has_shutdown() && plugin.is_healthy(self.data)) {
+            let (data, runner) = (self.data, plugin.clone());
+            let result = tracing::info_span!("shutdown", plugin = plugin.name).in_scope(|| watchdog::call(self.deadlines.end(), move || {
+                let _entered = data.stats().enter(&runner.name);
+                runner.lib.shutdown(data)
+            }));
+            if let Err(missed) = result {
+                self.missed(&plugin.name, "shutdown", missed);
+            }
+        }
+    }
+
+    /// Calls end on all plugins, in the order of end_order<br>
+    /// The libraries stay loaded, as the values might still be read afterwards
+    pub fn end_all(&self) {
+        let _guard = self.lifecycle.lock().unwrap_or_else(|e| e.into_inner());
+        for plugin in self.end_order() {
+            self.end(&plugin);
+        }
+    }
+
+    /// A plugin ends before the plugins it got with get_plugin (see Data::dependencies).<br>
+    /// Apart from that, and for plugins that use each other, the ones loaded last end first
+    fn end_order(&self) -> Vec<Arc<Loaded>> {
+        let mut left: Vec<Arc<Loaded>> = self.plugins.read().expect("Unable to read plugin list").iter().rev().cloned().collect();
+        let uses: HashMap<String, HashSet<String>> = left.iter().map(|plugin| (plugin.name.clone(), self.data.dependencies(&plugin.name))).collect();
+
+        let mut order = Vec::with_capacity(left.len());
+        while !left.is_empty() {
+            let unused = left.iter().position(|plugin| !left.iter().any(|other| other.name != plugin.name && uses[&other.name].contains(&plugin.name)));
+            let next = unused.unwrap_or_else(|| {
+                let names: Vec<&str> = left.iter().map(|plugin| plugin.name.as_str()).collect();
+                warn!("Plugins {} use each other, ending them in reverse load order", names.join(", "));
+                0
+            });
+            order.push(left.remove(next));
+        }
+        order
+    }
+
+    /// Stops the plugin, calls its end and closes the library<br>
+    /// The values of the plugins it registered are deleted, so it can be loaded again
+    pub fn unload(&self, name: &str) -> Result<(), String> {
+        self.stop_updates(name)?;
+        let _guard = self.lifecycle.lock().unwrap_or_else(|e| e.into_inner());
+        self.unload_locked(name).map(|_| ())
+    }
+
+    /// Stops the update loop of the plugin and waits for it, without holding the lifecycle lock meanwhile
+    fn stop_updates(&self, name: &str) -> Result<(), String> {
+        let plugin = self.get(name).ok_or(format!("{} is not loaded", name))?;
+        plugin.stop.store(true, Ordering::Release);
+        self.join(&plugin);
+        Ok(())
+    }
+
+    fn unload_locked(&self, name: &str) -> Result<Arc<Loaded>, String> {
+        let plugin = {
+            let mut plugins = self.plugins.write().expect("Unable to write plugin list");
+            let index = plugins.iter().position(|p| p.name == name).ok_or(format!("{} is not loaded", name))?;
+            plugins.remove(index)
+        };
+
+        plugin.stop.store(true, Ordering::Release);
+        self.join(&plugin);
+        self.end(&plugin);
+
+        for registered in &plugin.registered {
+            let deleted = self.data.purge_plugin(registered);
+            info!("Unloaded plugin {}, deleted its {} values", registered, deleted);
+        }
+
+        // The library itself is closed once the last reference is dropped
+        Ok(plugin)
+    }
+
+    /// Unloads the plugin and loads it from the same file again, restarting its updates if they were running
+    pub fn reload(&self, name: &str) -> Result<(), String> {
+        self.stop_updates(name)?;
+        let _guard = self.lifecycle.lock().unwrap_or_else(|e| e.into_inner());
+
+        let old = self.unload_locked(name)?;
+        let path = old.path.clone();
+        let schedule = *old.schedule.lock().unwrap_or_else(|e| e.into_inner());
+        drop(old);
+
+        self.load_locked(name, &path)?;
+        if let (Some(interval), Some(plugin)) = (schedule, self.get(name)) {
+            self.start_loaded(plugin, interval);
+        }
+        Ok(())
+    }
+
+    /// Hands the changed config section to the plugin, if it exports config_changed<br>
+    /// The update deadline applies
+    pub fn config_changed(&self, name: &str, config: PluginConfig) {
+        if let Some(plugin) = self.get(name).filter(|plugin| plugin.is_healthy(self.data)) {
+            let (data, runner) = (self.data, plugin.clone());
+            let result = tracing::info_span!("config_changed", plugin = name).in_scope(|| watchdog::call(self.deadlines.update(), move || {
+                let _entered = data.stats().enter(&runner.name);
+                runner.lib.config_changed(data, config)
+            }));
+            if let Err(missed) = result {
+                self.missed(name, "config_changed", missed);
+            }
+        }
+    }
+
+    /// All loaded libraries, in the order they were loaded
+    pub fn plugins(&self) -> Vec<LoadedInfo> {
+        self.plugins.read().expect("Unable to read plugin list").iter().map(|p| (p, self.data.stats().get(&p.name).unwrap_or_default())).map(|(p, stats)| LoadedInfo {
+            name: p.name.clone(),
+            path: p.path.clone(),
+            registered: p.registered.clone(),
+            updating: p.update.lock().unwrap_or_else(|e| e.into_inner()).as_ref().is_some_and(|h| !h.is_finished()),
+            update_calls: stats.update_calls,
+            update_time: stats.update_time,
+            last_update: Duration::from_nanos(p.last_update_nanos.load(Ordering::Relaxed)),
+            unhealthy: stats.unhealthy
+        }).collect()
+    }
+
+    /// Loaded libraries that are unhealthy, with the reason
+    pub fn unhealthy(&self) -> Vec<(String, String)> {
+        self.plugins().into_iter().filter_map(|info| info.unhealthy.map(|reason| (info.name, reason))).collect()
+    }
+
+    /// Number of plugins that failed to load, init or panicked in update, and of loaded ones that are unhealthy
+    pub fn failed(&self) -> usize {
+        let unhealthy = self.plugins.read().expect("Unable to read plugin list").iter().filter(|p| !p.is_healthy(self.data)).count();
+        self.failed.load(Ordering::Acquire) + unhealthy
+    }
+}
@@ -0,0 +1,267 @@
+//! REST api over the Datastore, so external tools can read and write values<br>
+//! <br>
+//! GET /keys?pattern=glob lists keys, GET /values/{key} reads a value, PUT /values/{key} writes one (the body is the json value).<br>
+//! GET /plugins lists the plugins, POST /plugins/{name}/interact calls interact with {"methode": .., "args": ..}.<br>
+//! GET /stats lists the runtime statistics of all plugins, GET /stats/{name} those of one.<br>
+//! GET /metrics serves the numeric values and loader internals for Prometheus, unless disabled.<br>
+//! Writing and interacting require the configured token, values are written with the admin token.
+
+use std::{collections::HashMap, io::{self, Read}, sync::Arc, thread};
+
+use log::{info, warn};
+use plugin_sdk::{Datastore, KeyPattern, PluginStats, Value};
+use serde::Deserialize;
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{config::HttpConfig, datastore::Data, host::Host, metrics};
+
+/// Larger bodies are refused
+const MAX_BODY: u64 = 1024 * 1024;
+
+/// Answered as {"error": message}
+struct HttpError(u16, String);
+
+impl HttpError {
+    fn not_found(what: String) -> HttpError {
+        HttpError(404, format!("{} does not exist", what))
+    }
+}
+
+/// Everything besides the metrics is answered with json
+enum Reply {
+    Json(serde_json::Value),
+    Metrics(String)
+}
+
+struct Context {
+    data: &'static Data,
+    host: &'static Host,
+    token: Option<String>,
+    metrics: bool
+}
+
+/// Starts serving on the configured address
+pub fn spawn(config: &HttpConfig, data: &'static Data, host: &'static Host) -> io::Result<()> {
+    let server = Arc::new(Server::http(&config.bind).map_err(io::Error::other)?);
+    info!("Serving the REST api on http://{}", config.bind);
+    if config.token.is_none() {
+        warn!("No http.token is set, writing values and interacting with plugins over http is disabled");
+    }
+
+    let context = Arc::new(Context { data, host, token: config.token.clone(), metrics: config.metrics });
+    for _ in 0..config.workers {
+        let (server, context) = (server.clone(), context.clone());
+        thread::spawn(move || {
+            while let Ok(mut request) = server.recv() {
+                let (status, body, content_type) = match handle(&mut request, &context) {
+                    Ok(Reply::Json(body)) => (200, body.to_string(), "application/json"),
+                    Ok(Reply::Metrics(body)) => (200, body, metrics::CONTENT_TYPE),
+                    Err(HttpError(status, message)) => (status, json!({ "error": message }).to_string(), "application/json")
+                };
+
+                let header = Header::from_bytes("Content-Type", content_type).expect("Valid header");
+                let response = Response::from_string(body).with_status_code(status).with_header(header);
+                if let Err(e) = request.respond(response) {
+                    warn!("Failed to answer http request: {}", e);
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle(request: &mut Request, context: &Context) -> Result<Reply, HttpError> {
+    if let (Method::Get, "/metrics", true) = (request.method(), request.url(), context.metrics) {
+        return Ok(Reply::Metrics(metrics::render(context.data, context.host)));
+    }
+    route(request, context).map(Reply::Json)
+}
+
+fn route(request: &mut Request, context: &Context) -> Result<serde_json::Value, HttpError> {
+    let data = context.data;
+    let (path, query) = split_url(request.url());
+    let segments: Vec<&str> = path.iter().map(String::as_str).collect();
+
+    match (request.method(), segments.as_slice()) {
+        (Method::Get, ["keys"]) => {
+            let pattern = query.get("pattern").map(|glob| KeyPattern::Glob(glob.clone())).unwrap_or(KeyPattern::All);
+            let keys: Vec<serde_json::Value> = data.list_keys(&pattern).into_iter().map(|info| json!({
+                "key": info.key,
+                "owner": info.owner,
+                "type": info.value_type.to_string()
+            })).collect();
+            Ok(json!(keys))
+        },
+        (Method::Get, ["values", key]) => {
+            let handle = data.get_data_handle(key).ok_or(HttpError::not_found(key.to_string()))?;
+            let value = data.get_value(&handle).map_err(|_| HttpError::not_found(key.to_string()))?;
+            let metadata = data.get_metadata(&handle).unwrap_or_default();
+            Ok(json!({
+                "key": key,
+                "type": value.value_type().to_string(),
+                "value": value_to_json(&value),
+                "display": metadata.display(&value),
+                "metadata": metadata
+            }))
+        },
+        (Method::Put, ["values", key]) => {
+            authorize(request, context)?;
+            let handle = data.get_data_handle(key).ok_or(HttpError::not_found(key.to_string()))?;
+            let json: serde_json::Value = serde_json::from_str(&read_body(request)?).map_err(|e| HttpError(400, e.to_string()))?;
+            let value = json_to_value(json).ok_or(HttpError(400, "expected a number, string or bool".to_string()))?;
+
+            data.set_value(&handle, data.loader_token(), value).map_err(|_| HttpError(422, format!("unable to set {}, the value does not fit its type or range, or it is read only", key)))?;
+            let value = data.get_value(&handle).map_err(|_| HttpError::not_found(key.to_string()))?;
+            Ok(json!({ "key": key, "value": value_to_json(&value) }))
+        },
+        (Method::Get, ["plugins"]) => {
+            let loaded = context.host.plugins();
+            let plugins: Vec<serde_json::Value> = data.plugins().into_iter().map(|plugin| {
+                let lib = loaded.iter().find(|lib| lib.registered.contains(&plugin.name));
+                json!({
+                    "name": plugin.name,
+                    "version": plugin.version,
+                    "online": plugin.is_online(),
+                    "library": lib.map(|lib| lib.name.clone()),
+                    "healthy": lib.is_none_or(|lib| lib.unhealthy.is_none())
+                })
+            }).collect();
+            Ok(json!(plugins))
+        },
+        (Method::Get, ["stats"]) => {
+            Ok(json!(data.stats().all().iter().map(|(name, stats)| stats_to_json(name, stats)).collect::<Vec<_>>()))
+        },
+        (Method::Get, ["stats", name]) => {
+            let stats = data.get_plugin_stats(name).ok_or(HttpError::not_found(format!("stats for {}", name)))?;
+            Ok(stats_to_json(name, &stats))
+        },
+        (Method::Post, ["plugins", name, "interact"]) => {
+            authorize(request, context)?;
+            #[derive(Deserialize)]
+            struct Interact {
+                methode: String,
+                #[serde(default)]
+                args: String
+            }
+
+            let call: Interact = serde_json::from_str(&read_body(request)?).map_err(|e| HttpError(400, e.to_string()))?;
+            let plugin = data.get_plugin(&name.to_string()).ok_or(HttpError::not_found(name.to_string()))?;
+            let result = plugin.interact(call.methode, call.args).map_err(|_| HttpError(502, format!("{} returned an error", name)))?;
+            Ok(json!({ "result": result }))
+        },
+        (_, ["keys"] | ["values", _] | ["plugins"] | ["plugins", _, "interact"] | ["stats"] | ["stats", _]) => Err(HttpError(405, "method not allowed".to_string())),
+        _ => Err(HttpError(404, "unknown endpoint".to_string()))
+    }
+}
+
+/// Checks the bearer token of the request
+fn authorize(request: &Request, context: &Context) -> Result<(), HttpError> {
+    let Some(token) = &context.token else {
+        return Err(HttpError(403, "writing is disabled, no http.token is configured".to_string()));
+    };
+
+    let sent = request.headers().iter().find(|h| h.field.equiv("Authorization")).and_then(|h| h.value.as_str().strip_prefix("Bearer "));
+    if !sent.is_some_and(|sent| constant_time_eq(sent.as_bytes(), token.as_bytes())) {
+        return Err(HttpError(401, "missing or wrong token".to_string()));
+    }
+    Ok(())
+}
+
+/// Compares without returning early on the first difference, so the time taken doesn't tell how much of the token was right<br>
+/// Only the length can be told apart
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b));
+    std::hint::black_box(diff) == 0
+}
+
+fn read_body(request: &mut Request) -> Result<String, HttpError> {
+    let mut body = String::new();
+    request.as_reader().take(MAX_BODY + 1).read_to_string(&mut body).map_err(|e| HttpError(400, e.to_string()))?;
+    if body.len() as u64 > MAX_BODY {
+        return Err(HttpError(413, "body too large".to_string()));
+    }
+    Ok(body)
+}
+
+/// Times are in seconds
+fn stats_to_json(name: &str, stats: &PluginStats) -> serde_json::Value {
+    json!({
+        "name": name,
+        "init_secs": stats.init_time.as_secs_f64(),
+        "update_secs": stats.update_time.as_secs_f64(),
+        "update_calls": stats.update_calls,
+        "end_secs": stats.end_time.as_secs_f64(),
+        "gets": stats.gets,
+        "sets": stats.sets,
+        "creates": stats.creates,
+        "interacts_served": stats.interacts_served,
+        "interacts_made": stats.interacts_made,
+        "errors": stats.errors,
+        "last_error": stats.last_error,
+        "unhealthy": stats.unhealthy
+    })
+}
+
+/// Values as plain json, without the type tag they are serialized with
+pub(crate) fn value_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Int(i) => json!(i),
+        Value::Float(f) => json!(f),
+        Value::Bool(b) => json!(b),
+        Value::Str(s) => json!(s)
+    }
+}
+
+/// The Datastore converts the value into the type of the key
+pub(crate) fn json_to_value(json: serde_json::Value) -> Option<Value> {
+    match json {
+        serde_json::Value::Bool(b) => Some(Value::Bool(b)),
+        serde_json::Value::Number(n) => n.as_i64().map(Value::Int).or(n.as_f64().map(Value::Float)),
+        serde_json::Value::String(s) => Some(Value::Str(s)),
+        _ => None
+    }
+}
+
+/// Splits the url into the decoded path segments and query parameters
+fn split_url(url: &str) -> (Vec<String>, HashMap<String, String>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+
+    let segments = path.split('/').filter(|s| !s.is_empty()).map(|s| percent_decode(s, false)).collect();
+    let query = query.split('&').filter(|s| !s.is_empty()).map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (percent_decode(key, true), percent_decode(value, true))
+    }).collect();
+
+    (segments, query)
+}
+
+/// Decodes %XX escapes (and + in queries), invalid escapes are kept as they are
+fn percent_decode(text: &str, query: bool) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        out.push(byte);
+                        i += 3;
+                        continue;
+                    },
+                    None => out.push(b'%')
+                }
+            },
+            b'+' if query => out.push(b' '),
+            byte => out.push(byte)
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,78 @@
+//! Looks into a plugin library without initializing it<br>
+//! Used by `list` and `check`, so only symbols are resolved and the manifest is read, no plugin code besides that is run.
+
+use std::{ffi::CStr, os::raw::c_char, path::{Path, PathBuf}};
+
+use dlopen2::wrapper::{WrapperApi, Container};
+use plugin_sdk::{Datastore, Manifest, PluginConfig, SDK_VERSION, RUSTC_VERSION};
+
+// The derive only understands plain fn types, so no alias for init
+#[allow(clippy::type_complexity)]
+#[derive(WrapperApi)]
+struct InspectWrapper {
+    plugin_manifest: Option<extern "C" fn() -> *const c_char>,
+    init: Option<fn(storage: &'static dyn Datastore) -> Result<(), String>>,
+    update: Option<fn(storage: &'static dyn Datastore)>,
+    end: Option<fn(storage: &'static dyn Datastore)>,
+    config_changed: Option<fn(storage: &'static dyn Datastore, config: PluginConfig)>,
+    shutdown: Option<fn(storage: &'static dyn Datastore)>
+}
+
+#[derive(Debug, Clone)]
+pub struct Inspection {
+    pub path: PathBuf,
+    /// None if the plugin does not export one (or it is unreadable)
+    pub manifest: Option<Manifest>,
+    /// The plugin functions the library exports
+    pub exports: Vec<&'static str>
+}
+
+/// Opens the library and reads what it exports
+pub fn inspect(path: &Path) -> Result<Inspection, String> {
+    let lib = unsafe { Container::<InspectWrapper>::load(path.as_os_str()) }.map_err(|e| e.to_string())?;
+
+    let manifest = lib.plugin_manifest().and_then(|ptr| {
+        if ptr.is_null() {
+            return None;
+        }
+        let text = unsafe { CStr::from_ptr(ptr) }.to_str().ok()?;
+        toml::from_str(text).ok()
+    });
+
+    let exports = [
+        ("init", lib.has_init()),
+        ("update", lib.has_update()),
+        ("end", lib.has_end()),
+        ("config_changed", lib.has_config_changed()),
+        ("shutdown", lib.has_shutdown())
+    ].into_iter().filter(|(_, has)| *has).map(|(name, _)| name).collect();
+
+    Ok(Inspection { path: path.to_path_buf(), manifest, exports })
+}
+
+impl Inspection {
+    /// Everything that keeps this plugin from being loaded safely, empty if it is compatible
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        for required in ["init", "end"] {
+            if !self.exports.contains(&required) {
+                problems.push(format!("does not export {}", required));
+            }
+        }
+
+        match &self.manifest {
+            None => problems.push("has no manifest, unable to verify the sdk and compiler it was built with (add plugin_sdk::export_manifest!())".to_string()),
+            Some(manifest) => {
+                if manifest.sdk_version != SDK_VERSION {
+                    problems.push(format!("was built against sdk {}, the loader uses {}", manifest.sdk_version, SDK_VERSION));
+                }
+                if manifest.rustc_version != RUSTC_VERSION {
+                    problems.push(format!("was built with {}, the loader with {}", manifest.rustc_version, RUSTC_VERSION));
+                }
+            }
+        }
+
+        problems
+    }
+}
@@ -0,0 +1,202 @@
+//! Append only journal of everything the plugins did to the Datastore<br>
+//! One JSON object per line, so a journal cut off by a crash is still readable up to the last line.
+
+use std::{fs::{File, OpenOptions}, io::{self, Write, BufRead, BufReader}, path::Path, sync::Mutex, time::{SystemTime, UNIX_EPOCH, Duration}, collections::{HashMap, VecDeque}, thread};
+
+use plugin_sdk::{Value, Metadata, AccessToken, Datastore, Plugin};
+use serde::{Serialize, Deserialize};
+
+use crate::datastore::{Data, LOADER_NAME};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Microseconds since the unix epoch
+    pub time: u64,
+    pub plugin: String,
+    pub event: JournalEvent
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum JournalEvent {
+    Register { version: String },
+    Deregister,
+    Create { key: String, value: Value, metadata: Metadata },
+    Set { key: String, value: Value },
+    Delete { key: String },
+    Rename { key: String, new_key: String },
+    /// The loader finished initializing the plugins
+    Initialized
+}
+
+impl JournalEntry {
+    /// The entry for an event happening now
+    pub fn new(plugin: &str, event: JournalEvent) -> JournalEntry {
+        JournalEntry { time: now_micros(), plugin: plugin.to_string(), event }
+    }
+}
+
+pub struct Journal {
+    file: Mutex<File>
+}
+
+impl Journal {
+    /// Opens the journal, appending to it if it exists already
+    pub fn open(path: &Path) -> io::Result<Journal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Journal { file: Mutex::new(file) })
+    }
+
+    /// Writes the event, each event is written out immediately so nothing is lost if we crash
+    pub fn record(&self, plugin: &str, event: JournalEvent) {
+        let entry = JournalEntry::new(plugin, event);
+
+        let Ok(mut line) = serde_json::to_string(&entry) else {
+            return;
+        };
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::error!("Failed to write to journal: {}", e);
+        }
+    }
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or_default()
+}
+
+/// A recorded journal, to be fed into the Datastore<br>
+/// <br>
+/// Every recorded plugin is registered under its name, if a plugin of that name is already registered<br>
+/// (because it is loaded and under test) all of its events are skipped, so the live plugin is not interfered with.<br>
+/// Events of the loader itself (like preloaded values) are applied with the loader token.<br>
+/// What was recorded before init finished is applied by init, so it can still write values that are read only after init.
+pub struct Replay {
+    entries: VecDeque<JournalEntry>,
+    tokens: HashMap<String, Option<AccessToken>>,
+    applied: usize
+}
+
+impl Replay {
+    /// Reads the whole journal, lines that can't be read are skipped
+    pub fn open(path: &Path) -> io::Result<Replay> {
+        let reader = BufReader::new(File::open(path)?);
+
+        let mut entries = VecDeque::new();
+        for (nr, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push_back(entry),
+                Err(e) => log::warn!("Skipping line {} of the journal: {}", nr + 1, e)
+            }
+        }
+
+        Ok(Replay { entries, tokens: HashMap::new(), applied: 0 })
+    }
+
+    /// To be called before Data::finish_init<br>
+    /// Applies everything up to where the recording finished init, as fast as possible.<br>
+    /// Journals recorded without that point only have their leading registers and creates applied
+    pub fn init(&mut self, data: &Data) {
+        let marked = self.entries.iter().any(|entry| matches!(entry.event, JournalEvent::Initialized));
+        while let Some(entry) = self.entries.front() {
+            match entry.event {
+                JournalEvent::Initialized => {
+                    self.entries.pop_front();
+                    return;
+                },
+                JournalEvent::Register { .. } | JournalEvent::Create { .. } => (),
+                _ if marked => (),
+                _ => return
+            }
+
+            let entry = self.entries.pop_front().expect("Checked above");
+            self.apply(data, entry);
+        }
+    }
+
+    /// Applies the rest of the journal<br>
+    /// With realtime the events are spaced out like they were recorded, else they are applied as fast as possible.<br>
+    /// Returns the number of events that were applied, including those of init
+    pub fn run(mut self, data: &Data, realtime: bool) -> usize {
+        let mut start: Option<(u64, std::time::Instant)> = None;
+        while let Some(entry) = self.entries.pop_front() {
+            if realtime {
+                let (first, started) = *start.get_or_insert((entry.time, std::time::Instant::now()));
+                let due = Duration::from_micros(entry.time.saturating_sub(first));
+                if let Some(wait) = due.checked_sub(started.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+
+            self.apply(data, entry);
+        }
+
+        // Plugins that did not deregister in the recording stay registered, just like they would be
+        self.applied
+    }
+
+    fn apply(&mut self, data: &Data, entry: JournalEntry) {
+        if apply(data, &mut self.tokens, entry).is_ok() {
+            self.applied += 1;
+        }
+    }
+}
+
+fn apply(data: &Data, tokens: &mut HashMap<String, Option<AccessToken>>, entry: JournalEntry) -> Result<(), ()> {
+    if let JournalEvent::Register { version } = &entry.event {
+        let token = data.register_plugin(Plugin::new(entry.plugin.clone(), version.clone(), None));
+        tokens.insert(entry.plugin, token);
+        return Ok(());
+    }
+
+    // The loader is always registered, but not recorded as such
+    let token = match entry.plugin.as_str() {
+        LOADER_NAME => data.loader_token().clone(),
+        _ => tokens.get(&entry.plugin).cloned().flatten().ok_or(())?
+    };
+    match entry.event {
+        JournalEvent::Register { .. } => unreachable!("Handled above"),
+        JournalEvent::Initialized => return Err(()),
+        JournalEvent::Deregister => {
+            data.deregister_plugin(&token);
+            tokens.remove(&entry.plugin);
+        },
+        JournalEvent::Create { key, value, metadata } => {
+            data.create_value_with_metadata(key, &token, value, metadata)?;
+        },
+        JournalEvent::Set { key, value } => {
+            data.set_value(&data.get_data_handle(&key).ok_or(())?, &token, value)?;
+        },
+        JournalEvent::Delete { key } => {
+            data.delete_value(&data.get_data_handle(&key).ok_or(())?, &token)?;
+        },
+        JournalEvent::Rename { key, new_key } => {
+            data.rename_value(&data.get_data_handle(&key).ok_or(())?, &token, new_key)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_finite_floats_survive_the_journal() {
+        for float in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY, 1.5] {
+            let entry = JournalEntry::new("test", JournalEvent::Set { key: "Float".to_string(), value: Value::Float(float) });
+            let line = serde_json::to_string(&entry).unwrap();
+            let JournalEvent::Set { value: Value::Float(read), .. } = serde_json::from_str::<JournalEntry>(&line).unwrap().event else {
+                panic!("Read back a different event from {}", line);
+            };
+            assert!(read == float || (read.is_nan() && float.is_nan()), "{} was read back as {}", float, read);
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! The loader side of the plugin system<br>
+//! Split out of the binary so the Datastore can be used by benchmarks and other tools
+
+pub mod admin;
+pub mod config;
+pub mod datastore;
+pub mod export;
+mod expression;
+pub mod host;
+pub mod http;
+pub mod inspect;
+pub mod journal;
+mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod persistence;
+pub mod remote;
+pub mod replication;
+mod segmented;
+pub mod shutdown;
+pub mod stats;
+pub mod trace;
+mod value_store;
+pub mod watchdog;
+pub mod websocket;
@@ -0,0 +1,52 @@
+//! Accepting connections on tcp or a unix socket, for the admin console and remote plugins<br>
+//! Every connection is served on a thread of its own, handed a reader and a writer of the same stream.
+
+use std::{fs, io::{self, Read, Write}, net::{TcpListener, TcpStream}, os::unix::{fs::FileTypeExt, net::{UnixListener, UnixStream}}, path::Path, sync::Arc, thread};
+
+use log::error;
+
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+/// Accepts connections on the tcp address
+pub fn tcp(bind: &str, what: &'static str, serve: impl Fn(TcpStream, TcpStream) + Send + Sync + 'static) -> io::Result<()> {
+    let listener = TcpListener::bind(bind)?;
+    accept(move || listener.accept().map(|(stream, _)| stream), what, serve);
+    Ok(())
+}
+
+/// Accepts connections on the unix socket, a socket left over from a previous run is replaced
+pub fn socket(path: &Path, what: &'static str, serve: impl Fn(UnixStream, UnixStream) + Send + Sync + 'static) -> io::Result<()> {
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    accept(move || listener.accept().map(|(stream, _)| stream), what, serve);
+    Ok(())
+}
+
+fn accept<S: Stream>(mut next: impl FnMut() -> io::Result<S> + Send + 'static, what: &'static str, serve: impl Fn(S, S) + Send + Sync + 'static) {
+    let serve = Arc::new(serve);
+    thread::spawn(move || loop {
+        match next().and_then(|stream| Ok((stream.try_clone()?, stream))) {
+            Ok((reader, writer)) => {
+                let serve = serve.clone();
+                thread::spawn(move || serve(reader, writer));
+            },
+            Err(e) => error!("Failed to accept {}: {}", what, e)
+        }
+    });
+}
@@ -0,0 +1,110 @@
+//! The logger of the loader, writing to stderr and optionally into a file<br>
+//! Stdout is left to the plugins and to commands like dump<br>
+//! Plugins log through the Datastore, their records are tagged with the plugin name and version and filtered with the level of their library
+
+use std::{collections::HashMap, fmt::Write as _, fs::{File, OpenOptions}, io::Write, path::Path, sync::Mutex};
+
+use log::{Level, Log, Metadata, Record, LevelFilter};
+use plugin_sdk::{LogLevel, LogRecord, Value};
+
+/// Target of records from plugins, followed by the library name
+const PLUGIN_TARGET: &str = "plugin::";
+
+struct Logger {
+    file: Option<Mutex<File>>,
+    level: LevelFilter,
+    /// Levels for single plugins, by their library name (like `[plugin.<name>]`)
+    plugins: HashMap<String, LevelFilter>
+}
+
+impl Logger {
+    fn level(&self, target: &str) -> LevelFilter {
+        match target.strip_prefix(PLUGIN_TARGET) {
+            Some(plugin) => self.plugins.get(plugin).copied().unwrap_or(self.level),
+            None => self.level
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Plugin records carry their name and version in the module path
+        let line = match (record.target().strip_prefix(PLUGIN_TARGET), record.module_path()) {
+            (Some(_), Some(plugin)) => format!("[{} {}] {}", record.level(), plugin, record.args()),
+            _ => format!("[{} {}] {}", record.level(), record.target(), record.args())
+        };
+        eprintln!("{}", line);
+
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap_or_else(|e| e.into_inner()).flush();
+        }
+    }
+}
+
+/// Installs the logger, can only be called once<br>
+/// plugins overrides the level for single plugins
+pub fn init(level: LevelFilter, plugins: HashMap<String, LevelFilter>, file: Option<&Path>) -> std::io::Result<()> {
+    let file = match file {
+        Some(path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        None => None
+    };
+
+    // The log macros check the max level first, so it has to let through the most verbose plugin
+    let max = plugins.values().copied().fold(level, Ord::max);
+    if log::set_boxed_logger(Box::new(Logger { file, level, plugins })).is_ok() {
+        log::set_max_level(max);
+    }
+    Ok(())
+}
+
+/// Returns if a record of this level from a plugin of the library would be logged
+pub fn plugin_enabled(library: &str, level: LogLevel) -> bool {
+    let target = format!("{}{}", PLUGIN_TARGET, library);
+    log::logger().enabled(&Metadata::builder().level(to_level(level)).target(&target).build())
+}
+
+/// Logs a record of the plugin, with the fields appended as key=value<br>
+/// It is filtered by the library, but shown with the name the plugin registered under
+pub fn log_plugin(library: &str, plugin: &str, version: &str, record: LogRecord) {
+    let mut message = record.message;
+    for (key, value) in &record.fields {
+        let _ = match value {
+            Value::Str(s) => write!(message, " {}={:?}", key, s),
+            value => write!(message, " {}={}", key, value.to_string())
+        };
+    }
+
+    let target = format!("{}{}", PLUGIN_TARGET, library);
+    let identity = format!("{} v{}", plugin, version);
+    log::logger().log(&Record::builder()
+        .level(to_level(record.level))
+        .target(&target)
+        .module_path(Some(&identity))
+        .args(format_args!("{}", message))
+        .build());
+}
+
+fn to_level(level: LogLevel) -> Level {
+    match level {
+        LogLevel::Error => Level::Error,
+        LogLevel::Warn => Level::Warn,
+        LogLevel::Info => Level::Info,
+        LogLevel::Debug => Level::Debug,
+        LogLevel::Trace => Level::Trace
+    }
+}
@@ -1,261 +1,552 @@
-use std::{sync::{RwLock, atomic::{AtomicI64, Ordering, AtomicU64, AtomicBool}, Mutex, Arc}, collections::{HashMap, hash_map::DefaultHasher}, fs, thread, hash::Hasher};
-use std::hash::Hash;
+use std::{fs, thread, path::{Path, PathBuf}, time::{Duration, SystemTime}, env, process::ExitCode, net::ToSocketAddrs};
+
+use log::{info, warn, error, LevelFilter};
+use plugin_sdk::Datastore;
+use pluginloader::{datastore::Data, persistence, journal::{self, Journal}, export::{self, Format}, config::{Config, ConfigError, DEFAULT_CONFIG_PATH}, logging, trace, inspect, host::Host, admin, http, websocket, remote, replication, shutdown::Shutdown};
+
+/// How often the config file is checked for changes
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bad arguments or config
+const EXIT_USAGE: u8 = 1;
+/// A plugin failed to load, init, panicked or did not stop cleanly (for check: a plugin is not compatible)
+const EXIT_PLUGIN_FAILED: u8 = 2;
+
+const USAGE: &str = "\
+Usage: pluginloader [command] [options]
+
+Commands:
+  run                 Loads and runs the plugins (the default)
+  dump                Like run, then prints the whole store to stdout
+  list [dir]...       Prints the manifests of the plugins in the directories, without initializing them
+  check <file>...     Checks if the plugin libraries are compatible with this loader
+  help                Prints this
+
+Options:
+  --config <file>     Config to use instead of pluginloader.toml
+  --plugins <dir>     Directory to load plugins from, replaces plugins.paths (can be given multiple times)
+  --journal <file>    Records all changes to the Datastore
+  --replay <file>     Replays a recorded journal while the plugins run
+  --preload <file>    Creates the values in this json/toml file before the plugins are initialized
+  --export <file>     Writes the whole store into this json/toml file before shutting down
+  --trace <file>      Writes trace spans of the plugin calls and Datastore operations into this file (Chrome trace format)
+  --format <format>   json or toml, the output of dump
+  --admin <console>   Opens the admin console on stdin, or on the unix socket at this path
+  --http <address>    Serves the REST api on this address, like 127.0.0.1:8080
+  --websocket <addr>  Streams changes to websocket clients on this address, like 127.0.0.1:8081
+  --remote <address>  Accepts remote plugins on this tcp address, or on the unix socket at this path
+  --replicate <peer>  Mirrors the values of the loader with remote plugins on this address
+
+SIGINT and SIGTERM shut down like the admin shutdown command, a second one exits right away.
+Exit codes: 0 on success, 1 for bad arguments or config, 2 if a plugin failed (or is incompatible for check)";
+
+fn main() -> ExitCode {
+    let (command, mut args) = match Args::parse(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    match command {
+        Command::Help => {
+            println!("{}", USAGE);
+            ExitCode::SUCCESS
+        },
+        Command::Check => check(&args.positional),
+        Command::List | Command::Run | Command::Dump => {
+            // The directories given to list replace plugins.paths, so they are validated instead
+            if command == Command::List && !args.positional.is_empty() {
+                args.plugins = args.positional.clone();
+            }
+            let config = match load_config(&args) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::from(EXIT_USAGE);
+                }
+            };
 
-use dlopen2::wrapper::{WrapperApi, Container};
-use plugin_sdk::{Datastore, Plugin, Value, AccessToken, DataHandle};
+            if let Err(e) = logging::init(config.logging.level_filter().unwrap_or(LevelFilter::Info), config.logging.plugin_filters(), config.logging.file.as_deref()) {
+                eprintln!("Unable to open log file: {}", e);
+                return ExitCode::from(EXIT_USAGE);
+            }
+            if let Some(path) = &config.logging.trace {
+                if let Err(e) = trace::init(path) {
+                    eprintln!("Unable to open trace file {}: {}", path.display(), e);
+                    return ExitCode::from(EXIT_USAGE);
+                }
+            }
 
+            let code = match command {
+                Command::List => list(&config),
+                Command::Dump => {
+                    let format = args.format.unwrap_or(Format::Json);
+                    run(args, config, Some(format))
+                },
+                _ => run(args, config, None)
+            };
+            trace::finish();
+            log::logger().flush();
+            code
+        }
+    }
+}
+
+/// Loads and runs all plugins, printing the store in the format of dump at the end<br>
+/// Returns EXIT_PLUGIN_FAILED if any plugin failed
+fn run(args: Args, config: Config, dump: Option<Format>) -> ExitCode {
+    // Before anything slow, so a signal during preload or init still shuts down in order
+    let shutdown: &'static Shutdown = Box::leak(Box::new(Shutdown::new()));
+    if let Err(e) = shutdown.on_signals() {
+        error!("Failed to handle signals: {}", e);
+    }
 
-fn main() {
     let data: &'static Data = Box::leak(Box::new(Data::new()));
     // data.create_value("Test".to_string(), Value::Str("Hello World!".to_string())).unwrap();
 
-    let plugins: &'static mut Vec<Container<PluginWrapper>> = Box::leak(Box::new(vec![]));
+    let persistence = &config.persistence;
+    if let Some(path) = &persistence.journal {
+        match Journal::open(path) {
+            Ok(journal) => {
+                data.set_journal(journal);
+            },
+            Err(e) => error!("Failed to open journal {}: {}", path.display(), e)
+        }
+    }
 
-    if let Ok(mut res) = fs::read_dir("lib") {
-        while let Some(Ok(item)) = res.next() {
-            println!("Found plugin {}", item.file_name().to_str().unwrap());
+    if let Some(path) = persistence.snapshot() {
+        match persistence::load(path) {
+            Ok(values) => data.restore(values),
+            Err(e) => error!("Failed to load snapshot from {}: {}", path.display(), e)
+        }
+        let path: &'static Path = Box::leak(path.to_path_buf().into_boxed_path());
+        persistence::spawn_periodic(data, path, Duration::from_secs(persistence.snapshot_interval_secs));
+    }
 
-            match unsafe { Container::<PluginWrapper>::load(item.path().as_os_str()) } {
-                Ok(cont) => {
-                    match cont.init(data) {
-                        Ok(()) => plugins.push(cont),
-                        Err(e) => println!("Failed to load plugin {}", e)
-                    };
-                },
-                Err(e) => println!("Failed to load plugin {}", e)
-            }
+    if let Some(path) = &persistence.preload {
+        match export::preload(data, path) {
+            Ok(count) => info!("Preloaded {} values from {}", count, path.display()),
+            Err(e) => error!("Failed to preload {}: {}", path.display(), e)
         }
     }
 
+    data.set_plugin_configs(config.plugin.clone());
 
-    let mut threads = vec![];
+    if let Some(deadline) = config.watchdog.interact() {
+        data.set_interact_deadline(deadline);
+    }
+    let host: &'static Host = Box::leak(Box::new(Host::new(data, config.watchdog)));
 
-    for p in plugins.iter() {
-        if p.has_update() {
-            threads.push(thread::spawn(move | |  { p.update(data); }));
+    for (name, path) in find_plugins(&config) {
+        if shutdown.is_requested() {
+            info!("Not loading the remaining plugins, shutting down");
+            break;
         }
-        
+        // Failures are logged and counted by the host
+        let _ = host.load(&name, &path);
     }
 
-    // Either way, we need to give the thread time to finish execution before we access Answer, else we race the thread and run into a None
-    for handle in threads {
-        handle.join().expect("One of the plugins died...");
-    }
-    thread::sleep(std::time::Duration::from_millis(10));
+    // Started after the plugins are loaded, so they keep their names and only the others are replayed.
+    // What was recorded during init is replayed before it is finished here as well
+    let replay = persistence.replay.clone().and_then(|path| match journal::Replay::open(&path) {
+        Ok(mut replay) => {
+            replay.init(data);
+            Some(thread::spawn(move || {
+                let count = replay.run(data, true);
+                info!("Replayed {} events from {}", count, path.display());
+            }))
+        },
+        Err(e) => {
+            error!("Failed to replay {}: {}", path.display(), e);
+            None
+        }
+    });
 
-    println!("{}", data.get_value(&data.get_data_handle("Answer").unwrap()).unwrap().to_string());
+    data.finish_init();
 
-    // Cleaning out the plugins
-    for p in plugins.iter() {
-        p.end(data);
+    if config.admin.stdin {
+        admin::spawn_stdin(data, host, shutdown);
     }
-}
-
-struct Data {
-    key_map: RwLock<HashMap<String, usize>>,
-    plugins: RwLock<HashMap<String, InteralPlugin>>,
-    data_store: RwLock<Vec<DataContainer>>
-}
-
-impl Datastore for Data {
-    fn create_value(&self, key: String, access_token: &AccessToken, val_type: Value) -> Result<DataHandle,()> {
-        if let (Ok(mut map),Ok(mut store)) = (self.key_map.write(), self.data_store.write()) {
-            // We should prepend the namespace for this specific plugin...
-            // or not, too complicated for this prototype
-            if map.contains_key(&key) {
-                return Err(());
-            }
+    if let Some(path) = &config.admin.socket {
+        if let Err(e) = admin::spawn_socket(path, data, host, shutdown) {
+            error!("Failed to open admin socket {}: {}", path.display(), e);
+        }
+    }
+    if config.http.enabled {
+        if let Err(e) = http::spawn(&config.http, data, host) {
+            error!("Failed to serve http on {}: {}", config.http.bind, e);
+        }
+    }
+    if config.websocket.enabled {
+        if let Err(e) = websocket::spawn(&config.websocket, data) {
+            error!("Failed to listen for websockets on {}: {}", config.websocket.bind, e);
+        }
+    }
+    if let Some(bind) = &config.remote.bind {
+        if let Err(e) = remote::spawn_tcp(bind, data) {
+            error!("Failed to accept remote plugins on {}: {}", bind, e);
+        }
+    }
+    if let Some(path) = &config.remote.socket {
+        if let Err(e) = remote::spawn_socket(path, data) {
+            error!("Failed to accept remote plugins on {}: {}", path.display(), e);
+        }
+    }
+    replication::spawn(&config.replication, data);
 
-            let mut hasher = DefaultHasher::new();
-            key.hash(&mut hasher);
+    watch_config(args, config.clone(), data, host);
 
+    for info in host.plugins().into_iter().take_while(|_| !shutdown.is_requested()) {
+        host.start(&info.name, config.update_interval(&info.name).map(Duration::from_millis));
+    }
+    host.spawn_watchdog();
+
+    // With an admin console or a server we keep running until asked to shut down
+    let run_for = config.scheduler.run_for_secs.map(Duration::from_secs);
+    let serving = config.admin.stdin || config.admin.socket.is_some() || config.http.enabled || config.websocket.enabled
+        || config.remote.bind.is_some() || config.remote.socket.is_some() || config.replication.peer.is_some();
+    if !(run_for.is_some() || serving) {
+        // Otherwise we run until all update calls are done, unless a signal arrives first
+        thread::spawn(|| {
+            host.wait();
+            shutdown.request();
+        });
+    }
+    shutdown.wait(run_for);
+    host.stop_all();
+    host.notify_shutdown();
+
+    // Either way, we need to give the threads time to finish execution before we end the plugins
+    host.wait();
+    if let Some(replay) = replay {
+        replay.join().expect("Replay died...");
+    }
+    thread::sleep(std::time::Duration::from_millis(10));
 
-            let handle = DataHandle { index: store.len(), name_hash: hasher.finish() };
+    // Cleaning out the plugins, the ones loaded last first
+    host.end_all();
 
-            map.insert(key.clone(), handle.index.clone());
-            store.push(DataContainer { name: key, value: ValueStore::from(val_type), owner: access_token.clone(), name_hash: handle.name_hash.clone() });
+    if let Some(path) = persistence.snapshot() {
+        if let Err(e) = persistence::save(data, path) {
+            error!("Failed to save snapshot to {}: {}", path.display(), e);
+        }
+    }
 
+    if let Some(path) = &persistence.export {
+        if let Err(e) = export::export(data, path) {
+            error!("Failed to export to {}: {}", path.display(), e);
+        }
+    }
 
-            Ok(handle)
-        } else {
-            Err(())
+    if let Some(format) = dump {
+        match export::to_string(&export::dump(data), format) {
+            Ok(text) => println!("{}", text),
+            Err(e) => error!("Failed to dump the store: {}", e)
         }
     }
 
-    fn set_value(&self, handle: &DataHandle, access_token: &AccessToken, val: Value) -> Result<(),()> {
-        if let Ok(store) = self.data_store.read() {
-            if let Some(cont) = store.get(handle.index) {
-                if cont.name_hash != handle.name_hash {
-                    return Err(()); // Name was updated, so the handle is outdated
-                }
-                
-                if &cont.owner != access_token {
-                    return Err(());
-                    // This is not the owner, therefore does not have write permission
-                }
+    for (name, reason) in host.unhealthy() {
+        error!("Plugin {} did not stop cleanly, it is unhealthy: {}", name, reason);
+    }
+    let failed = host.failed();
+    if failed > 0 {
+        error!("{} plugins failed", failed);
+        return ExitCode::from(EXIT_PLUGIN_FAILED);
+    }
+    ExitCode::SUCCESS
+}
 
-                if cont.value.update(val).is_ok() {
-                    return Ok(());
-                } else {
-                    return Err(());
+/// Prints the manifest of every plugin in the plugin paths (the directories given to list replace them)
+fn list(config: &Config) -> ExitCode {
+    for (name, path) in plugin_files(&config.plugins.paths()) {
+        let enabled = if config.is_enabled(&name) { "" } else { " (disabled)" };
+        println!("{}{}: {}", name, enabled, path.display());
+
+        match inspect::inspect(&path) {
+            Ok(inspection) => {
+                match &inspection.manifest {
+                    Some(manifest) => {
+                        println!("  {} {}", manifest.name, manifest.version);
+                        if let Some(description) = &manifest.description {
+                            println!("  {}", description);
+                        }
+                        println!("  sdk {}, {}", manifest.sdk_version, manifest.rustc_version);
+                    },
+                    None => println!("  no manifest")
                 }
-            } else {
-                return Err(());
-            }
-        } else {
-            Err(())
+                println!("  exports {}", inspection.exports.join(", "));
+            },
+            Err(e) => println!("  unable to open: {}", e)
         }
     }
 
-    fn get_value(&self, handle: &DataHandle) -> Result<Value,()> {
-        if let Ok(store) = self.data_store.read() {
-            if let Some(cont) = store.get(handle.index) {
-                if cont.name_hash != handle.name_hash {
-                    return Err(()); // Name was updated, so the handle is outdated
-                }
+    ExitCode::SUCCESS
+}
 
-                return Ok(cont.value.read());
-            } else {
-                return Err(());
-            }
-        } else {
-            Err(())
-        }
+/// Checks the libraries against this loader, returns EXIT_PLUGIN_FAILED if any of them is incompatible
+fn check(files: &[PathBuf]) -> ExitCode {
+    if files.is_empty() {
+        eprintln!("check needs at least one plugin library\n\n{}", USAGE);
+        return ExitCode::from(EXIT_USAGE);
     }
 
-    fn register_plugin(&self, plugin: Plugin) -> Option<AccessToken> {
-        let mut l = self.plugins.write().expect("Unable to write to plugin list");
-        if l.contains_key(&plugin.name) {
-            return None;
+    let mut failed = false;
+    for path in files {
+        let problems = match inspect::inspect(path) {
+            Ok(inspection) => inspection.problems(),
+            Err(e) => vec![format!("unable to open: {}", e)]
+        };
+
+        if problems.is_empty() {
+            println!("{}: ok", path.display());
+        } else {
+            failed = true;
+            println!("{}: incompatible", path.display());
+            for problem in problems {
+                println!("  {}", problem);
+            }
         }
+    }
 
-        let access_token = AccessToken::new(plugin.name.clone() + "your mum"); // TODO implement a secure token system
-        l.insert(plugin.name.clone(), InteralPlugin { plugin, access_token: access_token.clone(), switchoff_handle: Arc::new(AtomicBool::new(false)) });
-        Some(access_token)
+    if failed {
+        ExitCode::from(EXIT_PLUGIN_FAILED)
+    } else {
+        ExitCode::SUCCESS
     }
+}
 
-    fn get_plugin(&self, name: &String) -> Option<Plugin> {
-        let r = self.plugins.read().expect("Unable to read plugin list");
-        if let Some(plugin) = r.get(name) {
-            return Some(plugin.plugin.renew(plugin.switchoff_handle.clone()));
-        }
+/// Reads the config and applies the command line on top of it
+fn load_config(args: &Args) -> Result<Config, ConfigError> {
+    let mut config = Config::load(args.config_path(), args.config.is_some())?;
 
-        None
+    if !args.plugins.is_empty() {
+        config.plugins.paths = Some(args.plugins.clone());
+    }
+    let persistence = &mut config.persistence;
+    persistence.journal = args.journal.clone().or(persistence.journal.take());
+    persistence.replay = args.replay.clone().or(persistence.replay.take());
+    persistence.preload = args.preload.clone().or(persistence.preload.take());
+    persistence.export = args.export.clone().or(persistence.export.take());
+    config.logging.trace = args.trace.clone().or(config.logging.trace.take());
+    match args.admin.as_deref() {
+        Some("stdin") => config.admin.stdin = true,
+        Some(path) => config.admin.socket = Some(PathBuf::from(path)),
+        None => ()
+    }
+    if let Some(bind) = &args.http {
+        config.http.enabled = true;
+        config.http.bind = bind.clone();
+    }
+    if let Some(bind) = &args.websocket {
+        config.websocket.enabled = true;
+        config.websocket.bind = bind.clone();
+    }
+    match &args.remote {
+        Some(address) if address.to_socket_addrs().is_ok() => config.remote.bind = Some(address.clone()),
+        Some(path) => config.remote.socket = Some(PathBuf::from(path)),
+        None => ()
+    }
+    if let Some(peer) = &args.replicate {
+        config.replication.peer = Some(peer.clone());
     }
 
-    fn deregister_plugin(&self, access_token: &AccessToken) -> bool {
-        let mut l = self.plugins.write().expect("Unable to write to plugin list");
-        let mut index: Option<String> = None;
+    config.validate()?;
+    Ok(config)
+}
 
-        for (name, plugin) in l.iter() {
-            if &plugin.access_token == access_token {
-                index = Some(name.clone());
-            }
+/// Polls the config file, and hands changed plugin sections to the plugins that export config_changed<br>
+/// Everything outside of the plugin sections is only read on startup
+fn watch_config(args: Args, mut current: Config, data: &'static Data, host: &'static Host) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    let mut last: Option<SystemTime> = modified(args.config_path());
+
+    thread::spawn(move || loop {
+        thread::sleep(CONFIG_POLL_INTERVAL);
+
+        let now = modified(args.config_path());
+        if now == last {
+            continue;
         }
+        last = now;
 
-        if let Some(index) = index {
-            if let Some(con) = l.remove(&index) {
-                // Interacting with the plugin is no longer possible
-                con.switchoff_handle.store(true, Ordering::Release); 
+        let config = match load_config(&args) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Not reloading {}: {}", args.config_path().display(), e);
+                continue;
             }
+        };
+        info!("Reloading {}", args.config_path().display());
+
+        let mut rest = config.clone();
+        rest.plugin = current.plugin.clone();
+        if rest != current {
+            warn!("Only the plugin sections are reloaded, restart the loader to apply the other changes");
         }
 
-        drop(l);
+        for name in data.set_plugin_configs(config.plugin.clone()) {
+            host.config_changed(&name, data.get_plugin_config(&name));
+        }
+        current = config;
+    });
+}
 
-        // We should also unload the propertys the plugins created
-        
+/// Name used in the config for a plugin file: the file name without `lib` and the extension
+fn plugin_name(path: &Path) -> Option<String> {
+    let stem = path.file_stem()?.to_str()?;
+    Some(stem.strip_prefix("lib").unwrap_or(stem).to_string())
+}
 
-        true
-    }
+/// All plugin libraries in the directories with their names, the first one wins if a name exists twice
+fn plugin_files(dirs: &[PathBuf]) -> Vec<(String, PathBuf)> {
+    let mut found: Vec<(String, PathBuf)> = vec![];
 
-    fn get_data_handle(&self, key: &str) -> Option<DataHandle> {
-        if let (Ok(map),Ok(store)) = (self.key_map.read(),self.data_store.read()) {
-            if let Some(addr) = map.get(key) {
-                if let Some(item) = store.get(addr.clone()) {
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            warn!("Unable to read plugin directory {}", dir.display());
+            continue;
+        };
 
-                    return Some(DataHandle { index: addr.clone(), name_hash: item.name_hash });
-                }
+        let mut paths: Vec<PathBuf> = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+        paths.sort();
+
+        for path in paths {
+            if path.extension().and_then(|ext| ext.to_str()) != Some(env::consts::DLL_EXTENSION) {
+                continue;
             }
+            let Some(name) = plugin_name(&path) else {
+                continue;
+            };
 
+            if let Some((_, first)) = found.iter().find(|(n, _)| n == &name) {
+                warn!("Skipping {}, plugin {} was already found at {}", path.display(), name, first.display());
+            } else {
+                found.push((name, path));
+            }
         }
-
-        None
     }
+
+    found
 }
 
-impl Data {
-    fn new() -> Data {
-        Data {key_map: RwLock::new(HashMap::<String,usize>::new()), plugins: RwLock::new(HashMap::<String,InteralPlugin>::new()), data_store: RwLock::new(vec![])}
+/// Collects all enabled plugins from the plugin paths, in the order they should be initialized
+fn find_plugins(config: &Config) -> Vec<(String, PathBuf)> {
+    let mut found: Vec<(String, PathBuf)> = plugin_files(&config.plugins.paths()).into_iter().filter(|(name, _)| config.is_enabled(name)).collect();
+
+    for name in config.plugins.enabled.iter().chain(config.plugins.order.iter()) {
+        if !found.iter().any(|(n, _)| n == name) {
+            warn!("Plugin {} is named in the config, but was not found", name);
+        }
     }
-}
 
-#[derive(WrapperApi)]
-struct PluginWrapper {
-    init: fn(storage: &'static dyn Datastore) -> Result<(), String>,
-    update: Option<fn(storage: &'static dyn Datastore)>,
-    end: fn(storage: &'static dyn Datastore)
+    let position = |name: &str| config.plugins.order.iter().position(|n| n == name).unwrap_or(usize::MAX);
+    found.sort_by(|(a, _), (b, _)| position(a).cmp(&position(b)).then(a.cmp(b)));
+    found
 }
 
+/// All options, each takes a value
+const OPTIONS: [&str; 13] = ["--config", "--plugins", "--journal", "--replay", "--preload", "--export", "--trace", "--format", "--admin", "--http", "--websocket", "--remote", "--replicate"];
 
-struct InteralPlugin {
-    plugin: Plugin,
-    access_token: AccessToken,
-    switchoff_handle: Arc<AtomicBool>
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Command {
+    Run,
+    Dump,
+    List,
+    Check,
+    Help
 }
 
-#[allow(dead_code)]
-struct DataContainer {
-    name: String,
-    owner: AccessToken,
-    name_hash: u64,
-    value: ValueStore
+/// Command line options, these take precedence over the config
+#[derive(Default, Clone)]
+struct Args {
+    /// Config file to use instead of pluginloader.toml, has to exist
+    config: Option<PathBuf>,
+    /// Directories to load plugins from, replaces plugins.paths
+    plugins: Vec<PathBuf>,
+    journal: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    preload: Option<PathBuf>,
+    export: Option<PathBuf>,
+    trace: Option<PathBuf>,
+    /// Output of dump
+    format: Option<Format>,
+    /// stdin or the path of a unix socket
+    admin: Option<String>,
+    /// Address of the http api, enables it
+    http: Option<String>,
+    /// Address of the websocket stream, enables it
+    websocket: Option<String>,
+    /// Tcp address or unix socket path remote plugins connect to
+    remote: Option<String>,
+    /// Remote plugin address of the loader to mirror
+    replicate: Option<String>,
+    /// Directories for list, libraries for check
+    positional: Vec<PathBuf>
 }
 
-pub enum ValueStore {
-    Int(AtomicI64),
-    Float(AtomicU64),
-    Bool(AtomicBool),
-    Str(Mutex<String>)
-}
+impl Args {
+    /// Without a command we run, so `pluginloader --config x.toml` keeps working
+    fn parse(mut iter: impl Iterator<Item = String>) -> Result<(Command, Args), String> {
+        let mut args = Args::default();
+        let mut command = Command::Run;
+        let mut first = true;
 
-impl From<Value> for ValueStore {
-    fn from(value: Value) -> Self {
-        match value {
-            Value::Int(i) => ValueStore::Int(AtomicI64::new(i)),
-            Value::Float(f) => ValueStore::Float(AtomicU64::new(u64::from_be_bytes(f.to_be_bytes()))),
-            Value::Bool(b) => ValueStore::Bool(AtomicBool::new(b)),
-            Value::Str(str) => ValueStore::Str(Mutex::new(str)),
-        }
-    }
-}
+        while let Some(arg) = iter.next() {
+            let is_first = first;
+            first = false;
 
-impl ValueStore {
-    pub fn update(&self, value: Value) -> Result<(), ()> {
-        match self {
-            ValueStore::Int(i) => i.store(value.try_into().ok().ok_or(())?, Ordering::Relaxed),
-            ValueStore::Float(f) => {
-                let float:f64 = value.try_into().ok().ok_or(())?;
+            if arg == "--help" {
+                command = Command::Help;
+                continue;
+            }
 
-                let by = float.to_be_bytes();
-                let u = u64::from_be_bytes(by);
-                f.store(u, Ordering::Relaxed);
-            },
-            ValueStore::Bool(b) => b.store(value.try_into().ok().ok_or(())?, Ordering::Relaxed),
-            ValueStore::Str(str) => *str.lock().unwrap() = value.into(),
+            if !arg.starts_with("--") {
+                match arg.as_str() {
+                    "run" if is_first => command = Command::Run,
+                    "dump" if is_first => command = Command::Dump,
+                    "list" if is_first => command = Command::List,
+                    "check" if is_first => command = Command::Check,
+                    "help" if is_first => command = Command::Help,
+                    _ if !is_first && matches!(command, Command::List | Command::Check) => args.positional.push(PathBuf::from(arg)),
+                    _ => return Err(format!("Unknown command {}", arg))
+                }
+                continue;
+            }
+
+            if !OPTIONS.contains(&arg.as_str()) {
+                return Err(format!("Unknown option {}", arg));
+            }
+            let Some(value) = iter.next() else {
+                return Err(format!("{} is missing its value", arg));
+            };
+            match arg.as_str() {
+                "--config" => args.config = Some(PathBuf::from(value)),
+                "--plugins" => args.plugins.push(PathBuf::from(value)),
+                "--journal" => args.journal = Some(PathBuf::from(value)),
+                "--replay" => args.replay = Some(PathBuf::from(value)),
+                "--preload" => args.preload = Some(PathBuf::from(value)),
+                "--export" => args.export = Some(PathBuf::from(value)),
+                "--trace" => args.trace = Some(PathBuf::from(value)),
+                "--admin" => args.admin = Some(value),
+                "--http" => args.http = Some(value),
+                "--websocket" => args.websocket = Some(value),
+                "--remote" => args.remote = Some(value),
+                "--replicate" => args.replicate = Some(value),
+                "--format" => args.format = Some(match value.as_str() {
+                    "json" => Format::Json,
+                    "toml" => Format::Toml,
+                    _ => return Err(format!("Unknown format {}, expected json or toml", value))
+                }),
+                _ => unreachable!("Checked against OPTIONS")
+            }
         }
 
-        Ok(())
+        Ok((command, args))
     }
 
-    pub fn read(&self) -> Value {
-        match self {
-            ValueStore::Int(i) => Value::Int(i.load(Ordering::Relaxed)),
-            ValueStore::Float(f) => {
-                let u = f.load(Ordering::Relaxed);
-                Value::Float(f64::from_be_bytes(u.to_be_bytes()))
-            },
-            ValueStore::Bool(b) => Value::Bool(b.load(Ordering::Relaxed)),
-            ValueStore::Str(str) => Value::Str(str.lock().unwrap().clone()),
-        }
+    fn config_path(&self) -> &Path {
+        self.config.as_deref().unwrap_or(Path::new(DEFAULT_CONFIG_PATH))
     }
 }
@@ -0,0 +1,94 @@
+//! Prometheus text exposition of the Datastore, served by the http api on /metrics<br>
+//! <br>
+//! Keys can't be metric names, so all Int, Float and Bool values are one gauge family with the key as label:<br>
+//! `pluginloader_value{key="Finish",owner="Second Plugin",unit="",type="Float"} 5.24` (Bools are 0 or 1).<br>
+//! Loader internals are prefixed with pluginloader_ as well.
+
+use std::fmt::Write;
+
+use plugin_sdk::{Datastore, KeyPattern, Value};
+
+use crate::{datastore::Data, host::Host};
+
+/// Content type of the text format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Renders all metrics
+pub fn render(data: &Data, host: &Host) -> String {
+    let mut out = String::new();
+
+    family(&mut out, "pluginloader_value", "gauge", "Int, Float and Bool values of the Datastore");
+    for info in data.list_keys(&KeyPattern::All) {
+        let Ok(value) = data.get_value(&info.handle) else {
+            continue;
+        };
+        let number = match value {
+            Value::Int(i) => i as f64,
+            Value::Float(f) => f,
+            Value::Bool(b) => if b { 1.0 } else { 0.0 },
+            Value::Str(_) => continue
+        };
+        let unit = data.get_metadata(&info.handle).ok().and_then(|metadata| metadata.unit).unwrap_or_default();
+        sample(&mut out, "pluginloader_value", &[("key", &info.key), ("owner", &info.owner), ("unit", &unit), ("type", &info.value_type.to_string())], number);
+    }
+
+    let plugins = data.plugins();
+    family(&mut out, "pluginloader_plugins", "gauge", "Registered plugins, including the loader itself");
+    sample(&mut out, "pluginloader_plugins", &[], plugins.len() as f64);
+    family(&mut out, "pluginloader_plugins_online", "gauge", "Registered plugins that can be interacted with");
+    sample(&mut out, "pluginloader_plugins_online", &[], plugins.iter().filter(|p| p.is_online()).count() as f64);
+    family(&mut out, "pluginloader_plugins_failed", "gauge", "Plugins that failed to load, init, or panicked in update, and loaded ones that are unhealthy");
+    sample(&mut out, "pluginloader_plugins_failed", &[], host.failed() as f64);
+
+    let libraries = host.plugins();
+    family(&mut out, "pluginloader_libraries", "gauge", "Loaded plugin libraries");
+    sample(&mut out, "pluginloader_libraries", &[], libraries.len() as f64);
+    family(&mut out, "pluginloader_update_calls_total", "counter", "Finished update calls per library");
+    for lib in &libraries {
+        sample(&mut out, "pluginloader_update_calls_total", &[("library", &lib.name)], lib.update_calls as f64);
+    }
+    family(&mut out, "pluginloader_update_seconds_total", "counter", "Time spent in update per library");
+    for lib in &libraries {
+        sample(&mut out, "pluginloader_update_seconds_total", &[("library", &lib.name)], lib.update_time.as_secs_f64());
+    }
+    family(&mut out, "pluginloader_last_update_seconds", "gauge", "Time the last update call of the library took");
+    for lib in &libraries {
+        sample(&mut out, "pluginloader_last_update_seconds", &[("library", &lib.name)], lib.last_update.as_secs_f64());
+    }
+
+    family(&mut out, "pluginloader_lock_wait_seconds_total", "counter", "Time spent waiting for the locks of the Datastore");
+    for (lock, wait) in data.lock_waits() {
+        sample(&mut out, "pluginloader_lock_wait_seconds_total", &[("lock", lock)], wait.waited().as_secs_f64());
+    }
+    family(&mut out, "pluginloader_lock_acquisitions_total", "counter", "How often the locks of the Datastore were taken");
+    for (lock, wait) in data.lock_waits() {
+        sample(&mut out, "pluginloader_lock_acquisitions_total", &[("lock", lock)], wait.acquired() as f64);
+    }
+
+    out
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels.iter().map(|(label, value)| format!("{}=\"{}\"", label, escape(value))).collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+
+    let _ = match value {
+        v if v.is_nan() => writeln!(out, " NaN"),
+        v if v == f64::INFINITY => writeln!(out, " +Inf"),
+        v if v == f64::NEG_INFINITY => writeln!(out, " -Inf"),
+        v => writeln!(out, " {}", v)
+    };
+}
+
+/// Escapes backslashes, quotes and newlines in label values
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
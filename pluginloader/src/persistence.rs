@@ -0,0 +1,59 @@
+use std::{collections::HashMap, fs, io, path::Path, process, sync::Mutex, thread, time::Duration};
+
+use plugin_sdk::Value;
+
+use crate::datastore::Data;
+
+/// Held while saving, so the periodic and the final snapshot don't overtake each other
+static SAVING: Mutex<()> = Mutex::new(());
+
+/// Writes all values flagged with persist into the file<br>
+/// The file is written next to it first and then renamed, so a crash never leaves a half written snapshot.<br>
+/// Saves don't run at the same time, the last one started wins.<br>
+/// Json has no inf or NaN, so such Floats are left out (their value is lost, not the whole snapshot)
+pub fn save(data: &Data, path: &Path) -> io::Result<()> {
+    let _saving = SAVING.lock().unwrap_or_else(|e| e.into_inner());
+    let mut values = data.snapshot();
+    values.retain(|key, value| match value {
+        Value::Float(f) if !f.is_finite() => {
+            log::warn!("Not saving {} into the snapshot, {} can't be stored in json", key, f);
+            false
+        },
+        _ => true
+    });
+    let json = serde_json::to_string_pretty(&values)?;
+
+    // Named after the process, so loaders sharing a snapshot don't write into the same file either
+    let tmp = path.with_extension(format!("{}.tmp", process::id()));
+    fs::write(&tmp, json)?;
+    fs::rename(tmp, path)
+}
+
+/// Reads a snapshot written by save<br>
+/// A missing file is not an error, as it is simply the first start. Entries that are not a valid Value are skipped
+pub fn load(path: &Path) -> io::Result<HashMap<String, Value>> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e)
+    };
+
+    let entries: HashMap<String, serde_json::Value> = serde_json::from_str(&json)?;
+    Ok(entries.into_iter().filter_map(|(key, entry)| match serde_json::from_value(entry) {
+        Ok(value) => Some((key, value)),
+        Err(e) => {
+            log::warn!("Skipping {} in snapshot {}: {}", key, path.display(), e);
+            None
+        }
+    }).collect())
+}
+
+/// Spawns a thread saving the snapshot every interval, for the case we never get to shut down properly
+pub fn spawn_periodic(data: &'static Data, path: &'static Path, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(e) = save(data, path) {
+            log::error!("Failed to save snapshot to {}: {}", path.display(), e);
+        }
+    });
+}
@@ -0,0 +1,349 @@
+//! Remote plugins, living in another process and connected over tcp or a unix socket<br>
+//! <br>
+//! The protocol is one json object per line in both directions, see the README for all requests.<br>
+//! Each connection registers one plugin and holds its AccessToken, everything it creates and sets is done with it.<br>
+//! Other plugins get an ordinary Plugin from get_plugin, their interact calls are forwarded to the connection.<br>
+//! Closing the connection deregisters the plugin and deletes its values, like unloading a native plugin.
+
+use std::{collections::{HashMap, HashSet}, io::{self, BufRead, BufReader, Read, Write}, path::Path, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}, mpsc::RecvTimeoutError}, thread, time::Duration};
+
+use log::info;
+use plugin_sdk::{AccessToken, Datastore, KeyInfo, KeyPattern, LogLevel, LogRecord, Metadata, Plugin, PluginMessage, Value};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{datastore::Data, http::{json_to_value, value_to_json}, journal::JournalEvent, listener};
+
+/// How many interact calls can wait to be sent to the remote plugin
+const INTERACT_QUEUE: usize = 16;
+/// How often the subscription thread checks if the connection is still open
+const CLOSED_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Changes kept for a subscribed plugin until they are sent, more are dropped
+const CHANGES_CAPACITY: usize = 1024;
+
+#[derive(Deserialize)]
+struct Envelope {
+    /// Echoed in the response, so requests can be matched up
+    #[serde(default)]
+    id: Option<u64>,
+    #[serde(flatten)]
+    request: Request
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    Register { name: String, version: String },
+    Deregister,
+    Create { key: String, value: serde_json::Value, #[serde(default)] metadata: Metadata },
+    Get { key: String },
+    Set { key: String, value: serde_json::Value },
+    Delete { key: String },
+    List { #[serde(default)] pattern: Option<String> },
+    Subscribe { keys: Vec<String> },
+    Unsubscribe { keys: Vec<String> },
+    Interact { plugin: String, methode: String, #[serde(default)] args: String },
+    Log { level: LogLevel, message: String, #[serde(default)] fields: serde_json::Map<String, serde_json::Value> }
+}
+
+impl Request {
+    /// Name of the request in the trace
+    fn op(&self) -> &'static str {
+        match self {
+            Request::Register { .. } => "register",
+            Request::Deregister => "deregister",
+            Request::Create { .. } => "create",
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Delete { .. } => "delete",
+            Request::List { .. } => "list",
+            Request::Subscribe { .. } => "subscribe",
+            Request::Unsubscribe { .. } => "unsubscribe",
+            Request::Interact { .. } => "interact",
+            Request::Log { .. } => "log"
+        }
+    }
+}
+
+/// Answer to an interact call the loader forwarded to the remote plugin
+#[derive(Deserialize)]
+struct InteractReply {
+    interact: u64,
+    #[serde(default)]
+    result: Option<String>
+}
+
+struct Connection {
+    data: &'static Data,
+    writer: Mutex<Box<dyn Write + Send>>,
+    /// Name and token of the plugin once it registered
+    plugin: Mutex<Option<(String, AccessToken)>>,
+    /// Glob patterns of the keys the plugin subscribed to
+    patterns: Mutex<Vec<String>>,
+    subscribed: AtomicBool,
+    /// Interact calls waiting for their answer
+    calls: Mutex<HashMap<u64, oneshot::Sender<Result<String, ()>>>>,
+    next_call: AtomicU64,
+    closed: AtomicBool
+}
+
+/// Accepts remote plugins on the tcp address
+pub fn spawn_tcp(bind: &str, data: &'static Data) -> io::Result<()> {
+    listener::tcp(bind, "remote plugin", move |reader, writer| serve(reader, writer, data))?;
+    info!("Accepting remote plugins on {}", bind);
+    Ok(())
+}
+
+/// Accepts remote plugins on the unix socket, a socket left over from a previous run is replaced
+pub fn spawn_socket(path: &Path, data: &'static Data) -> io::Result<()> {
+    listener::socket(path, "remote plugin", move |reader, writer| serve(reader, writer, data))?;
+    info!("Accepting remote plugins on {}", path.display());
+    Ok(())
+}
+
+fn serve(reader: impl Read, writer: impl Write + Send + 'static, data: &'static Data) {
+    let conn = Arc::new(Connection {
+        data,
+        writer: Mutex::new(Box::new(writer)),
+        plugin: Mutex::new(None),
+        patterns: Mutex::new(vec![]),
+        subscribed: AtomicBool::new(false),
+        calls: Mutex::new(HashMap::new()),
+        next_call: AtomicU64::new(1),
+        closed: AtomicBool::new(false)
+    });
+
+    for line in BufReader::new(reader).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Ok(reply) = serde_json::from_str::<InteractReply>(&line) {
+            if let Some(callback) = conn.calls.lock().unwrap_or_else(|e| e.into_inner()).remove(&reply.interact) {
+                let _ = callback.send(reply.result.ok_or(()));
+            }
+            continue;
+        }
+
+        let envelope: Envelope = match serde_json::from_str(&line) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                conn.send(json!({ "error": format!("invalid request: {}", e) }));
+                continue;
+            }
+        };
+
+        let id = envelope.id;
+        if let Request::Interact { plugin, methode, args } = envelope.request {
+            // Answered on its own thread, the called plugin might call back into this one
+            let conn = conn.clone();
+            thread::spawn(move || {
+                let _entered = conn.name().map(|name| conn.data.stats().enter(&name));
+                conn.respond(id, interact(conn.data, &plugin, methode, args))
+            });
+            continue;
+        }
+
+        let deregister = matches!(envelope.request, Request::Deregister);
+        conn.respond(id, conn.execute(envelope.request));
+        if deregister {
+            break;
+        }
+    }
+
+    conn.close();
+}
+
+fn interact(data: &Data, plugin: &str, methode: String, args: String) -> Result<serde_json::Value, String> {
+    let plugin = data.get_plugin(&plugin.to_string()).ok_or(format!("{} is not registered", plugin))?;
+    plugin.interact(methode, args).map(|answer| json!(answer)).map_err(|_| format!("{} returned an error", plugin.name))
+}
+
+impl Connection {
+    /// Writes a line to the remote plugin, a closed connection is noticed by the reading side
+    fn send(&self, message: serde_json::Value) {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(writer, "{}", message).and_then(|_| writer.flush());
+    }
+
+    fn respond(&self, id: Option<u64>, result: Result<serde_json::Value, String>) {
+        self.send(match result {
+            Ok(result) => json!({ "id": id, "result": result }),
+            Err(e) => json!({ "id": id, "error": e })
+        });
+    }
+
+    /// Name of the plugin once it registered
+    fn name(&self) -> Option<String> {
+        self.plugin.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|(name, _)| name.clone())
+    }
+
+    fn token(&self) -> Result<AccessToken, String> {
+        self.plugin.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|(_, token)| token.clone()).ok_or("register first".to_string())
+    }
+
+    fn execute(self: &Arc<Self>, request: Request) -> Result<serde_json::Value, String> {
+        let name = self.name();
+        let _span = tracing::info_span!("remote", plugin = name.as_deref().unwrap_or_default(), op = request.op()).entered();
+        let _entered = name.map(|name| self.data.stats().enter(&name));
+        let data = self.data;
+        let handle = |key: &str| data.get_data_handle(key).ok_or(format!("{} does not exist", key));
+        let value = |json: serde_json::Value| json_to_value(json).ok_or("expected a number, string or bool".to_string());
+
+        match request {
+            Request::Register { name, version } => {
+                let mut plugin = self.plugin.lock().unwrap_or_else(|e| e.into_inner());
+                if plugin.is_some() {
+                    return Err("already registered".to_string());
+                }
+
+                let (sender, receiver) = mpsc::channel(INTERACT_QUEUE);
+                let token = data.register_plugin(Plugin::new_with_channel(name.clone(), version.clone(), sender)).ok_or(format!("{} is already registered", name))?;
+                *plugin = Some((name.clone(), token));
+                info!("Remote plugin {} v{} registered", name, version);
+
+                let conn = self.clone();
+                thread::spawn(move || conn.forward_calls(receiver));
+                Ok(json!(name))
+            },
+            Request::Deregister => Ok(json!(null)),
+            Request::Create { key, value: initial, metadata } => {
+                data.create_value_with_metadata(key.clone(), &self.token()?, value(initial)?, metadata).map_err(|_| format!("unable to create {}, it exists already or the value does not fit the metadata", key))?;
+                Ok(json!(key))
+            },
+            Request::Get { key } => {
+                let handle = handle(&key)?;
+                let val = data.get_value(&handle).map_err(|_| format!("{} does not exist", key))?;
+                let metadata = data.get_metadata(&handle).unwrap_or_default();
+                Ok(json!({ "value": value_to_json(&val), "type": val.value_type().to_string(), "metadata": metadata }))
+            },
+            Request::Set { key, value: val } => {
+                data.set_value(&handle(&key)?, &self.token()?, value(val)?).map_err(|_| format!("unable to set {}, it is not owned by this plugin, read only, or the value does not fit", key))?;
+                Ok(json!(null))
+            },
+            Request::Delete { key } => {
+                data.delete_value(&handle(&key)?, &self.token()?).map_err(|_| format!("unable to delete {}, it is not owned by this plugin", key))?;
+                Ok(json!(null))
+            },
+            Request::List { pattern } => {
+                let pattern = pattern.map(KeyPattern::Glob).unwrap_or(KeyPattern::All);
+                Ok(json!(data.list_keys(&pattern).into_iter().map(|info| json!({ "key": info.key, "owner": info.owner, "type": info.value_type.to_string() })).collect::<Vec<_>>()))
+            },
+            Request::Subscribe { keys } => {
+                self.patterns.lock().unwrap_or_else(|e| e.into_inner()).extend(keys);
+                if !self.subscribed.swap(true, Ordering::AcqRel) {
+                    let conn = self.clone();
+                    let changes = data.subscribe(CHANGES_CAPACITY);
+                    thread::spawn(move || {
+                        // The keys the plugin was told about, to tell it which are gone after it fell behind
+                        let mut known = HashSet::new();
+                        while !conn.closed.load(Ordering::Acquire) {
+                            match changes.recv_timeout(CLOSED_POLL_INTERVAL) {
+                                Ok(entry) => conn.notify(entry.plugin, entry.event, &mut known),
+                                Err(RecvTimeoutError::Timeout) => (),
+                                Err(RecvTimeoutError::Disconnected) => return
+                            }
+                            if changes.lagged() {
+                                conn.resend(&mut known);
+                            }
+                        }
+                    });
+                }
+                Ok(json!(null))
+            },
+            Request::Unsubscribe { keys } => {
+                self.patterns.lock().unwrap_or_else(|e| e.into_inner()).retain(|pattern| !keys.contains(pattern));
+                Ok(json!(null))
+            },
+            Request::Log { level, message, fields } => {
+                // Fields that are no plain value are logged as their json
+                let fields = fields.into_iter().map(|(key, json)| (key, json_to_value(json.clone()).unwrap_or(Value::Str(json.to_string())))).collect();
+                data.log(&self.token()?, LogRecord { level, message, fields });
+                Ok(json!(null))
+            },
+            Request::Interact { .. } => unreachable!("Interact is answered on its own thread")
+        }
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        self.patterns.lock().unwrap_or_else(|e| e.into_inner()).iter().any(|glob| KeyPattern::Glob(glob.clone()).matches(key))
+    }
+
+    /// Sends the change as an event, if the plugin subscribed to the key
+    fn notify(&self, owner: String, event: JournalEvent, known: &mut HashSet<String>) {
+        // Renamed values are deleted under the old key and created under the new one
+        let (gone, created, changed) = match event {
+            JournalEvent::Create { key, value, metadata } => (None, Some((key, value, metadata)), None),
+            JournalEvent::Set { key, value } => (None, None, Some((key, value))),
+            JournalEvent::Delete { key } => (Some(key), None, None),
+            JournalEvent::Rename { key, new_key } => {
+                let handle = self.data.get_data_handle(&new_key);
+                let value = handle.as_ref().and_then(|handle| Some((self.data.get_value(handle).ok()?, self.data.get_metadata(handle).ok()?)));
+                (Some(key), value.map(|(value, metadata)| (new_key, value, metadata)), None)
+            },
+            JournalEvent::Register { .. } | JournalEvent::Deregister | JournalEvent::Initialized => return
+        };
+
+        if let Some(key) = gone.filter(|key| self.matches(key)) {
+            self.send(json!({ "event": "deleted", "key": key }));
+            known.remove(&key);
+        }
+        if let Some((key, value, metadata)) = created.filter(|(key, ..)| self.matches(key)) {
+            self.send(json!({ "event": "created", "key": key, "value": value_to_json(&value), "owner": owner, "metadata": metadata }));
+            known.insert(key);
+        }
+        if let Some((key, value)) = changed.filter(|(key, _)| self.matches(key)) {
+            self.send(json!({ "event": "changed", "key": key, "value": value_to_json(&value), "owner": owner }));
+            known.insert(key);
+        }
+    }
+
+    /// After changes were dropped, sends the current value of every subscribed key and the keys deleted in the meantime
+    fn resend(&self, known: &mut HashSet<String>) {
+        let infos: Vec<KeyInfo> = self.data.list_keys(&KeyPattern::All).into_iter().filter(|info| self.matches(&info.key)).collect();
+        known.retain(|key| {
+            let exists = infos.iter().any(|info| &info.key == key);
+            if !exists {
+                self.send(json!({ "event": "deleted", "key": key }));
+            }
+            exists
+        });
+
+        for info in infos {
+            if let Ok(value) = self.data.get_value(&info.handle) {
+                self.send(json!({ "event": "changed", "key": info.key, "value": value_to_json(&value), "owner": info.owner }));
+                known.insert(info.key);
+            }
+        }
+    }
+
+    /// Sends the interact calls of other plugins to the remote plugin<br>
+    /// Ends once the connection is closed and the last copy of the Plugin was dropped
+    fn forward_calls(&self, mut receiver: mpsc::Receiver<PluginMessage>) {
+        while let Some((methode, args, callback)) = receiver.blocking_recv() {
+            if self.closed.load(Ordering::Acquire) {
+                let _ = callback.send(Err(()));
+                continue;
+            }
+
+            let call = self.next_call.fetch_add(1, Ordering::Relaxed);
+            self.calls.lock().unwrap_or_else(|e| e.into_inner()).insert(call, callback);
+            self.send(json!({ "interact": call, "methode": methode, "args": args }));
+        }
+    }
+
+    /// Deregisters the plugin and deletes its values, calls still waiting for an answer fail
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.calls.lock().unwrap_or_else(|e| e.into_inner()).clear();
+
+        if let Some((name, _)) = self.plugin.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            let deleted = self.data.purge_plugin(&name);
+            info!("Remote plugin {} is gone, deleted its {} values", name, deleted);
+        }
+    }
+}
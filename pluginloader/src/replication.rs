@@ -0,0 +1,170 @@
+//! Mirrors values of a peer loader into this one<br>
+//! <br>
+//! Connects to the remote plugin port of the peer like a remote plugin would, without registering:<br>
+//! subscribes to the selected values, lists and gets those that exist already, then follows the events.<br>
+//! Mirrored values keep the owner from the peer and are read only here. When the peer goes away they are deleted,<br>
+//! and everything is mirrored again once it is back.
+
+use std::{collections::HashMap, io::{BufRead, BufReader, Write}, net::TcpStream, thread, time::Duration};
+
+use log::{info, warn, debug};
+use plugin_sdk::{Datastore, Metadata};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{config::ReplicationConfig, datastore::Data, http::json_to_value};
+
+/// How long to wait before connecting to the peer again
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Id of the list request, gets count up from here
+const LIST_ID: u64 = 0;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Incoming {
+    Event(Event),
+    Response { id: Option<u64>, #[serde(default)] result: serde_json::Value, #[serde(default)] error: Option<String> }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    Created { key: String, value: serde_json::Value, owner: String, #[serde(default)] metadata: Metadata },
+    Changed { key: String, value: serde_json::Value, owner: String },
+    Deleted { key: String }
+}
+
+#[derive(Deserialize)]
+struct Listed {
+    key: String,
+    owner: String
+}
+
+#[derive(Deserialize)]
+struct Got {
+    value: serde_json::Value,
+    #[serde(default)]
+    metadata: Metadata
+}
+
+/// Keeps mirroring the peer, reconnecting whenever the connection is lost
+pub fn spawn(config: &ReplicationConfig, data: &'static Data) {
+    let Some(peer) = config.peer.clone() else {
+        return;
+    };
+    let config = config.clone();
+
+    thread::spawn(move || {
+        let mut connected = true;
+        loop {
+            match TcpStream::connect(&peer) {
+                Ok(stream) => {
+                    info!("Mirroring values from {}", peer);
+                    connected = true;
+                    if let Err(e) = mirror(stream, &config, data) {
+                        warn!("Lost replication peer {}: {}", peer, e);
+                    }
+                    info!("Replication peer {} is gone, deleted {} mirrored values", peer, data.remove_mirrors());
+                },
+                // Only logged once, the peer might just not be started yet
+                Err(e) if connected => {
+                    warn!("Unable to connect to replication peer {}, retrying: {}", peer, e);
+                    connected = false;
+                },
+                Err(_) => ()
+            }
+            thread::sleep(RECONNECT_INTERVAL);
+        }
+    });
+}
+
+fn mirror(stream: TcpStream, config: &ReplicationConfig, data: &Data) -> Result<(), String> {
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    let mut send = |request: serde_json::Value| writeln!(writer, "{}", request).and_then(|_| writer.flush()).map_err(|e| e.to_string());
+
+    // Subscribing first, so nothing created while we list is missed
+    let mut globs = config.keys.clone();
+    if !config.plugins.is_empty() || globs.is_empty() {
+        globs = vec!["*".to_string()];
+    }
+    send(json!({ "op": "subscribe", "keys": globs }))?;
+    send(json!({ "id": LIST_ID, "op": "list" }))?;
+
+    // Gets waiting for their answer, with the key and owner they are for
+    let mut pending: HashMap<u64, (String, String)> = HashMap::new();
+    let mut next_id = LIST_ID + 1;
+    let mut get = |key: String, owner: String, pending: &mut HashMap<u64, (String, String)>| {
+        if pending.values().any(|(k, _)| *k == key) {
+            return Ok(());
+        }
+        pending.insert(next_id, (key.clone(), owner));
+        next_id += 1;
+        send(json!({ "id": next_id - 1, "op": "get", "key": key }))
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        let incoming: Incoming = match serde_json::from_str(&line) {
+            Ok(incoming) => incoming,
+            Err(e) => {
+                debug!("Ignoring replication message {}: {}", line, e);
+                continue;
+            }
+        };
+
+        match incoming {
+            Incoming::Event(Event::Created { key, value, owner, metadata }) if config.selects(&key, &owner) => {
+                pending.retain(|_, (k, _)| *k != key);
+                create(data, key, owner, value, metadata);
+            },
+            Incoming::Event(Event::Changed { key, value, owner }) if config.selects(&key, &owner) => {
+                let set = json_to_value(value).is_some_and(|value| data.set_mirrored(&key, value));
+                // A value we don't know yet, as it was created before we subscribed
+                if !set && data.get_data_handle(&key).is_none() {
+                    get(key, owner, &mut pending)?;
+                }
+            },
+            Incoming::Event(Event::Deleted { key }) => {
+                pending.retain(|_, (k, _)| *k != key);
+                data.delete_mirrored(&key);
+            },
+            Incoming::Event(_) => (),
+            Incoming::Response { id: Some(LIST_ID), result, error } => {
+                let listed: Vec<Listed> = serde_json::from_value(result).map_err(|e| error.unwrap_or(e.to_string()))?;
+                for Listed { key, owner } in listed {
+                    if config.selects(&key, &owner) && data.get_data_handle(&key).is_none() {
+                        get(key, owner, &mut pending)?;
+                    }
+                }
+            },
+            Incoming::Response { id: Some(id), result, error } => {
+                let Some((key, owner)) = pending.remove(&id) else {
+                    continue;
+                };
+                // An error means it was deleted in the meantime
+                if error.is_none() {
+                    let got: Got = serde_json::from_value(result).map_err(|e| e.to_string())?;
+                    create(data, key, owner, got.value, got.metadata);
+                }
+            },
+            Incoming::Response { error: Some(e), .. } => warn!("Replication peer refused a request: {}", e),
+            Incoming::Response { .. } => ()
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates the mirrored value, or sets it if it is mirrored already
+fn create(data: &Data, key: String, owner: String, value: serde_json::Value, metadata: Metadata) {
+    let Some(value) = json_to_value(value) else {
+        return;
+    };
+    if data.set_mirrored(&key, value.clone()) {
+        return;
+    }
+    if !data.create_mirror(key.clone(), owner.clone(), value, metadata) {
+        warn!("Unable to mirror {} of {}, a local value with that key exists", key, owner);
+    }
+}
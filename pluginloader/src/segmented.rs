@@ -0,0 +1,132 @@
+use std::sync::{OnceLock, Mutex, atomic::{AtomicUsize, Ordering}};
+
+/// Size of the first segment, every following segment doubles in size
+const BASE_SEGMENT_SIZE: usize = 64;
+/// With doubling segments this is enough to hold more entries than we could ever allocate
+const SEGMENT_COUNT: usize = (usize::BITS - BASE_SEGMENT_SIZE.trailing_zeros()) as usize;
+
+/// Append only storage, where reading an index never requires a lock<br>
+/// <br>
+/// Entries are placed into segments that are allocated once and never moved,<br>
+/// so a reference to an entry stays valid while new entries are pushed.<br>
+/// Only pushing takes a lock, to serialize the writers among themselves
+pub struct SegmentedVec<T> {
+    segments: [OnceLock<Box<[OnceLock<T>]>>; SEGMENT_COUNT],
+    len: AtomicUsize,
+    push_lock: Mutex<()>
+}
+
+impl<T> SegmentedVec<T> {
+    pub fn new() -> Self {
+        SegmentedVec { segments: std::array::from_fn(|_| OnceLock::new()), len: AtomicUsize::new(0), push_lock: Mutex::new(()) }
+    }
+
+    /// Returns the segment and the offset within it for an index
+    fn locate(index: usize) -> (usize, usize) {
+        let block = index / BASE_SEGMENT_SIZE + 1;
+        let segment = (usize::BITS - 1 - block.leading_zeros()) as usize;
+        let offset = index - BASE_SEGMENT_SIZE * ((1 << segment) - 1);
+
+        (segment, offset)
+    }
+
+    /// Appends the value, returning the index it was stored under
+    pub fn push(&self, value: T) -> usize {
+        let _guard = self.push_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let index = self.len.load(Ordering::Relaxed);
+        let (segment, offset) = Self::locate(index);
+
+        let seg = self.segments[segment].get_or_init(|| {
+            (0..(BASE_SEGMENT_SIZE << segment)).map(|_| OnceLock::new()).collect()
+        });
+        if seg[offset].set(value).is_err() {
+            unreachable!("Slot {} was filled twice, even though pushing is serialized", index);
+        }
+
+        self.len.store(index + 1, Ordering::Release);
+        index
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len() {
+            return None;
+        }
+
+        let (segment, offset) = Self::locate(index);
+        self.segments[segment].get()?.get(offset)?.get()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Default for SegmentedVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn locate_at_segment_boundaries() {
+        assert_eq!(SegmentedVec::<()>::locate(0), (0, 0));
+        assert_eq!(SegmentedVec::<()>::locate(BASE_SEGMENT_SIZE - 1), (0, BASE_SEGMENT_SIZE - 1));
+        assert_eq!(SegmentedVec::<()>::locate(BASE_SEGMENT_SIZE), (1, 0));
+        assert_eq!(SegmentedVec::<()>::locate(3 * BASE_SEGMENT_SIZE - 1), (1, 2 * BASE_SEGMENT_SIZE - 1));
+        assert_eq!(SegmentedVec::<()>::locate(3 * BASE_SEGMENT_SIZE), (2, 0));
+        assert_eq!(SegmentedVec::<()>::locate(7 * BASE_SEGMENT_SIZE), (3, 0));
+    }
+
+    #[test]
+    fn push_and_get_across_segments() {
+        let vec = SegmentedVec::new();
+        assert_eq!(vec.get(0), None);
+
+        for i in 0..(15 * BASE_SEGMENT_SIZE + 1) {
+            assert_eq!(vec.push(i), i);
+        }
+        assert_eq!(vec.len(), 15 * BASE_SEGMENT_SIZE + 1);
+        for index in [0, BASE_SEGMENT_SIZE - 1, BASE_SEGMENT_SIZE, 3 * BASE_SEGMENT_SIZE - 1, 3 * BASE_SEGMENT_SIZE, 15 * BASE_SEGMENT_SIZE] {
+            assert_eq!(vec.get(index), Some(&index));
+        }
+        assert_eq!(vec.get(15 * BASE_SEGMENT_SIZE + 1), None);
+    }
+
+    #[test]
+    fn get_while_pushing() {
+        const COUNT: usize = 10_000;
+        let vec = Arc::new(SegmentedVec::new());
+
+        let writers: Vec<_> = (0..4).map(|_| {
+            let vec = vec.clone();
+            thread::spawn(move || for _ in 0..COUNT / 4 {
+                let index = vec.push(0);
+                // Stored values are where push said, the reader checks them against their index
+                assert!(vec.get(index).is_some());
+            })
+        }).collect();
+
+        let reader = {
+            let vec = vec.clone();
+            thread::spawn(move || while vec.len() < COUNT {
+                let len = vec.len();
+                assert!(len == 0 || vec.get(len - 1).is_some(), "Entry {} is counted but not readable", len - 1);
+                assert!(vec.get(len).is_none() || vec.len() > len);
+            })
+        };
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        reader.join().unwrap();
+        assert_eq!(vec.len(), COUNT);
+        assert!((0..COUNT).all(|index| vec.get(index).is_some()));
+    }
+}
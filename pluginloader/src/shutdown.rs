@@ -0,0 +1,71 @@
+//! Lets other threads (like the admin console) and SIGINT/SIGTERM ask the loader to shut down<br>
+//! A second signal exits right away, without waiting for the plugins to stop
+
+use std::{io, process, sync::{Condvar, Mutex}, thread, time::{Duration, Instant}};
+
+use log::{info, error};
+use signal_hook::{consts::{SIGINT, SIGTERM}, iterator::Signals};
+
+/// Exit code when a second signal doesn't wait for the plugins, the same as for a failed plugin
+const EXIT_FORCED: i32 = 2;
+
+#[derive(Default)]
+pub struct Shutdown {
+    requested: Mutex<bool>,
+    changed: Condvar
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn request(&self) {
+        *self.requested.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        self.changed.notify_all();
+    }
+
+    /// Requests the shutdown on SIGINT or SIGTERM, from now on<br>
+    /// Only a second signal forces the exit, a shutdown requested otherwise (like by the admin console) still gets to finish
+    pub fn on_signals(&'static self) -> io::Result<()> {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        thread::spawn(move || {
+            let mut received = 0;
+            for signal in signals.forever() {
+                received += 1;
+                if received > 1 {
+                    error!("Received signal {} again, exiting without waiting for the plugins", signal);
+                    log::logger().flush();
+                    process::exit(EXIT_FORCED);
+                }
+                info!("Received signal {}, shutting down", signal);
+                self.request();
+            }
+        });
+        Ok(())
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Blocks until shutdown was requested, or the timeout passed<br>
+    /// Returns true if it was requested
+    pub fn wait(&self, timeout: Option<Duration>) -> bool {
+        let end = timeout.map(|timeout| Instant::now() + timeout);
+        let mut requested = self.requested.lock().unwrap_or_else(|e| e.into_inner());
+
+        while !*requested {
+            requested = match end {
+                None => self.changed.wait(requested).unwrap_or_else(|e| e.into_inner()),
+                Some(end) => {
+                    let Some(rest) = end.checked_duration_since(Instant::now()) else {
+                        return false;
+                    };
+                    self.changed.wait_timeout(requested, rest).unwrap_or_else(|e| e.into_inner()).0
+                }
+            };
+        }
+        true
+    }
+}
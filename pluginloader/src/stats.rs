@@ -0,0 +1,237 @@
+//! Runtime statistics per plugin: time spent in init, update and end, Datastore and interact calls, and errors<br>
+//! <br>
+//! Plugins are counted under their library name like their config section, remote plugins under the name they registered.<br>
+//! Calls are attributed to the plugin whose entry point (or interact call) runs on the current thread.<br>
+//! On threads the plugin spawned itself only calls with an access token can be attributed, to the owner of the token.<br>
+//! A plugin that missed a deadline (see watchdog) is marked unhealthy here, calls to it fail from then on.
+
+use std::{cell::RefCell, collections::HashMap, sync::{Arc, Mutex, RwLock, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+
+use log::{debug, error};
+use plugin_sdk::{CallTracer, PluginStats};
+
+use crate::watchdog;
+
+thread_local! {
+    /// The plugin running on this thread
+    static CURRENT: RefCell<Option<(String, Arc<Counters>)>> = const { RefCell::new(None) };
+}
+
+/// Kinds of counted Datastore calls
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Get,
+    Set,
+    Create
+}
+
+/// Entry points that are timed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Init,
+    Update,
+    End
+}
+
+#[derive(Debug, Default)]
+pub struct Counters {
+    init_nanos: AtomicU64,
+    update_nanos: AtomicU64,
+    update_calls: AtomicU64,
+    end_nanos: AtomicU64,
+    gets: AtomicU64,
+    sets: AtomicU64,
+    creates: AtomicU64,
+    interacts_served: AtomicU64,
+    interacts_made: AtomicU64,
+    errors: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    unhealthy: Mutex<Option<String>>
+}
+
+impl Counters {
+    pub fn count(&self, op: Op) {
+        let counter = match op {
+            Op::Get => &self.gets,
+            Op::Set => &self.sets,
+            Op::Create => &self.creates
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self, message: String) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(message);
+    }
+
+    /// The plugin is not called anymore, until it is loaded again
+    pub fn mark_unhealthy(&self, reason: String) {
+        self.error(reason.clone());
+        *self.unhealthy.lock().unwrap_or_else(|e| e.into_inner()) = Some(reason);
+    }
+
+    pub fn set_healthy(&self) {
+        *self.unhealthy.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Why the plugin is unhealthy, None while it is healthy
+    pub fn unhealthy(&self) -> Option<String> {
+        self.unhealthy.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn snapshot(&self) -> PluginStats {
+        let nanos = |counter: &AtomicU64| Duration::from_nanos(counter.load(Ordering::Relaxed));
+        PluginStats {
+            init_time: nanos(&self.init_nanos),
+            update_time: nanos(&self.update_nanos),
+            update_calls: self.update_calls.load(Ordering::Relaxed),
+            end_time: nanos(&self.end_nanos),
+            gets: self.gets.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            creates: self.creates.load(Ordering::Relaxed),
+            interacts_served: self.interacts_served.load(Ordering::Relaxed),
+            interacts_made: self.interacts_made.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            last_error: self.last_error.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+            unhealthy: self.unhealthy()
+        }
+    }
+}
+
+/// Attributes calls on this thread to the previous plugin again once dropped
+pub struct Entered {
+    previous: Option<(String, Arc<Counters>)>
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+fn enter(name: String, counters: Arc<Counters>) -> Entered {
+    Entered { previous: CURRENT.with(|current| current.borrow_mut().replace((name, counters))) }
+}
+
+/// Name of the plugin running on this thread
+pub fn current_name() -> Option<String> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|(name, _)| name.clone()))
+}
+
+/// Calls f with the counters of the plugin running on this thread, borrowed so nothing is cloned<br>
+/// Returns None if no plugin is running
+pub fn with_current<R>(f: impl FnOnce(&Counters) -> R) -> Option<R> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|(_, counters)| f(counters)))
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    plugins: RwLock<HashMap<String, Arc<Counters>>>
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    /// The counters of the plugin, created on first use
+    pub fn counters(&self, plugin: &str) -> Arc<Counters> {
+        if let Some(counters) = self.plugins.read().unwrap_or_else(|e| e.into_inner()).get(plugin) {
+            return counters.clone();
+        }
+        self.plugins.write().unwrap_or_else(|e| e.into_inner()).entry(plugin.to_string()).or_default().clone()
+    }
+
+    /// Attributes the calls on this thread to the plugin, until the guard is dropped
+    pub fn enter(&self, plugin: &str) -> Entered {
+        enter(plugin.to_string(), self.counters(plugin))
+    }
+
+    /// Runs the entry point of the plugin, adding the time it took
+    pub fn time<R>(&self, plugin: &str, phase: Phase, f: impl FnOnce() -> R) -> R {
+        let counters = self.counters(plugin);
+        let _entered = enter(plugin.to_string(), counters.clone());
+
+        let start = Instant::now();
+        let result = f();
+        let nanos = start.elapsed().as_nanos() as u64;
+
+        match phase {
+            Phase::Init => counters.init_nanos.fetch_add(nanos, Ordering::Relaxed),
+            Phase::Update => {
+                counters.update_calls.fetch_add(1, Ordering::Relaxed);
+                counters.update_nanos.fetch_add(nanos, Ordering::Relaxed)
+            },
+            Phase::End => counters.end_nanos.fetch_add(nanos, Ordering::Relaxed)
+        };
+        result
+    }
+
+    pub fn get(&self, plugin: &str) -> Option<PluginStats> {
+        self.plugins.read().unwrap_or_else(|e| e.into_inner()).get(plugin).map(|counters| counters.snapshot())
+    }
+
+    /// Statistics of all plugins, sorted by name
+    pub fn all(&self) -> Vec<(String, PluginStats)> {
+        let mut all: Vec<(String, PluginStats)> = self.plugins.read().unwrap_or_else(|e| e.into_inner()).iter().map(|(name, counters)| (name.clone(), counters.snapshot())).collect();
+        all.sort_by(|a, b| a.0.cmp(&b.0));
+        all
+    }
+}
+
+/// Handed out with every Plugin: counts interact and send calls for the caller and the callee,<br>
+/// attributes what the callee does to it, and puts the call into a trace span.<br>
+/// Interact calls with a deadline run on their own thread, a callee that misses it is marked unhealthy
+#[derive(Debug)]
+pub struct CallHook {
+    callee: String,
+    counters: Arc<Counters>,
+    deadline: Option<Duration>
+}
+
+impl CallHook {
+    pub fn new(callee: String, counters: Arc<Counters>, deadline: Option<Duration>) -> CallHook {
+        CallHook { callee, counters, deadline }
+    }
+}
+
+impl CallTracer for CallHook {
+    fn trace(&self, kind: &'static str, plugin: &str, methode: &str, call: Box<dyn FnOnce() -> Result<String, ()> + Send>) -> Result<String, ()> {
+        let span = match kind {
+            "send" => tracing::info_span!("send", plugin, methode, ok = tracing::field::Empty),
+            _ => tracing::info_span!("interact", plugin, methode, ok = tracing::field::Empty)
+        };
+        let _span = span.enter();
+
+        with_current(|caller| caller.interacts_made.fetch_add(1, Ordering::Relaxed));
+        self.counters.interacts_served.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(reason) = self.counters.unhealthy() {
+            debug!("Not calling {} {:?}, it is unhealthy: {}", plugin, methode, reason);
+            span.record("ok", false);
+            return Err(());
+        }
+
+        let (callee, counters) = (self.callee.clone(), self.counters.clone());
+        let deadline = if kind == "interact" { self.deadline } else { None };
+        let result = match watchdog::call(deadline, move || {
+            let _entered = enter(callee, counters);
+            call()
+        }) {
+            Ok(result) => result,
+            Err(missed) => {
+                error!("Plugin {} {} in interact {:?}, it is marked unhealthy and not called anymore", plugin, missed, methode);
+                self.counters.mark_unhealthy(format!("{} in interact {:?}", missed, methode));
+                span.record("ok", false);
+                return Err(());
+            }
+        };
+        if result.is_err() {
+            self.counters.error(format!("{} {:?} returned an error", kind, methode));
+        }
+
+        span.record("ok", result.is_ok());
+        result
+    }
+}
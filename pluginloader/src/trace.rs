@@ -0,0 +1,146 @@
+//! Tracing spans of the plugin entry points, calls between plugins and Datastore operations<br>
+//! <br>
+//! The spans are created in the loader only: plugins can't share its tracing globals across the dylib boundary,<br>
+//! so calls between plugins go through the CallHook the Datastore attaches to every Plugin it hands out (see stats).<br>
+//! A callee runs on the thread of its caller, so its Datastore operations become children of the interact span.<br>
+//! The spans are written as complete events of the Chrome trace format, open the file in chrome://tracing or Perfetto.
+
+use std::{fs::File, io::{self, BufWriter, Write}, path::Path, process, sync::{Arc, Mutex, OnceLock, atomic::{AtomicU64, Ordering}}, time::Instant};
+
+use serde_json::json;
+use tracing::{field::{Field, Visit}, span::{Attributes, Id, Record}, Subscriber};
+use tracing_subscriber::{layer::{Context, SubscriberExt}, registry::LookupSpan, Layer};
+
+/// The open trace file, to be finished on shutdown
+static OUTPUT: OnceLock<Arc<Mutex<Output>>> = OnceLock::new();
+
+struct Output {
+    writer: BufWriter<File>,
+    first: bool,
+    finished: bool
+}
+
+/// Chrome numbers threads, ThreadId can't be turned into one on stable
+fn thread_number() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static NUMBER: u64 = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    NUMBER.with(|number| *number)
+}
+
+/// Stored with every span until it is closed
+struct Timing {
+    start: Instant,
+    thread: u64,
+    args: serde_json::Map<String, serde_json::Value>
+}
+
+struct Fields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name().to_string(), json!(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+}
+
+struct ChromeLayer {
+    output: Arc<Mutex<Output>>,
+    start: Instant
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for ChromeLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut args = serde_json::Map::new();
+        attrs.record(&mut Fields(&mut args));
+        // Links spans that ended up on different threads, like calls to remote plugins
+        args.insert("id".to_string(), json!(id.into_u64()));
+        if let Some(parent) = span.parent() {
+            args.insert("parent".to_string(), json!(parent.id().into_u64()));
+        }
+
+        span.extensions_mut().insert(Timing { start: Instant::now(), thread: thread_number(), args });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                values.record(&mut Fields(&mut timing.args));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let Some(timing) = extensions.get::<Timing>() else {
+            return;
+        };
+
+        let event = json!({
+            "name": span.name(),
+            "cat": span.metadata().target(),
+            "ph": "X",
+            "ts": timing.start.duration_since(self.start).as_secs_f64() * 1e6,
+            "dur": timing.start.elapsed().as_secs_f64() * 1e6,
+            "pid": process::id(),
+            "tid": timing.thread,
+            "args": timing.args
+        });
+
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        if output.finished {
+            return;
+        }
+        let separator = if output.first { "" } else { ",\n" };
+        output.first = false;
+        let _ = write!(output.writer, "{}{}", separator, event);
+    }
+}
+
+/// Writes all spans into the file from now on, can only be called once
+pub fn init(path: &Path) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "[")?;
+
+    let output = Arc::new(Mutex::new(Output { writer, first: true, finished: false }));
+    let subscriber = tracing_subscriber::registry().with(ChromeLayer { output: output.clone(), start: Instant::now() });
+    if tracing::subscriber::set_global_default(subscriber).is_ok() {
+        let _ = OUTPUT.set(output);
+    }
+    Ok(())
+}
+
+/// Closes the json array and flushes the file, spans closed afterwards are lost
+pub fn finish() {
+    if let Some(output) = OUTPUT.get() {
+        let mut output = output.lock().unwrap_or_else(|e| e.into_inner());
+        if output.finished {
+            return;
+        }
+        output.finished = true;
+        let _ = writeln!(output.writer, "\n]").and_then(|_| output.writer.flush());
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::{Arc, atomic::{AtomicI64, AtomicU64, AtomicBool, Ordering}};
+
+use arc_swap::ArcSwap;
+use plugin_sdk::{Value, ValueType};
+
+/// Storage for a single Value<br>
+/// All variants can be read without taking a lock, Strings are swapped out as a whole
+pub enum ValueStore {
+    Int(AtomicI64),
+    Float(AtomicU64),
+    Bool(AtomicBool),
+    Str(ArcSwap<String>)
+}
+
+impl From<Value> for ValueStore {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Int(i) => ValueStore::Int(AtomicI64::new(i)),
+            Value::Float(f) => ValueStore::Float(AtomicU64::new(u64::from_be_bytes(f.to_be_bytes()))),
+            Value::Bool(b) => ValueStore::Bool(AtomicBool::new(b)),
+            Value::Str(str) => ValueStore::Str(ArcSwap::from_pointee(str)),
+        }
+    }
+}
+
+impl ValueStore {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            ValueStore::Int(_) => ValueType::Int,
+            ValueStore::Float(_) => ValueType::Float,
+            ValueStore::Bool(_) => ValueType::Bool,
+            ValueStore::Str(_) => ValueType::Str,
+        }
+    }
+
+    /// Converts the value into the type of this store, so update can not fail on it
+    pub fn coerce(&self, value: Value) -> Result<Value, ()> {
+        Ok(match self {
+            ValueStore::Int(_) => Value::Int(value.try_into().ok().ok_or(())?),
+            ValueStore::Float(_) => Value::Float(value.try_into().ok().ok_or(())?),
+            ValueStore::Bool(_) => Value::Bool(value.try_into().ok().ok_or(())?),
+            ValueStore::Str(_) => Value::Str(value.into()),
+        })
+    }
+
+    pub fn update(&self, value: Value) -> Result<(), ()> {
+        match (self, self.coerce(value)?) {
+            (ValueStore::Int(i), Value::Int(val)) => i.store(val, Ordering::Relaxed),
+            (ValueStore::Float(f), Value::Float(val)) => f.store(u64::from_be_bytes(val.to_be_bytes()), Ordering::Relaxed),
+            (ValueStore::Bool(b), Value::Bool(val)) => b.store(val, Ordering::Relaxed),
+            (ValueStore::Str(str), Value::Str(val)) => str.store(Arc::new(val)),
+            _ => unreachable!("coerce returns the type of the store")
+        }
+
+        Ok(())
+    }
+
+    pub fn compare_and_swap(&self, current: Value, new: Value) -> Result<bool, ()> {
+        match self {
+            ValueStore::Int(i) => {
+                let (current, new) = (current.try_into().ok().ok_or(())?, new.try_into().ok().ok_or(())?);
+                Ok(i.compare_exchange(current, new, Ordering::Relaxed, Ordering::Relaxed).is_ok())
+            },
+            ValueStore::Float(f) => {
+                let current: f64 = current.try_into().ok().ok_or(())?;
+                let new: f64 = new.try_into().ok().ok_or(())?;
+                Ok(f.compare_exchange(current.to_bits(), new.to_bits(), Ordering::Relaxed, Ordering::Relaxed).is_ok())
+            },
+            ValueStore::Bool(b) => {
+                let (current, new) = (current.try_into().ok().ok_or(())?, new.try_into().ok().ok_or(())?);
+                Ok(b.compare_exchange(current, new, Ordering::Relaxed, Ordering::Relaxed).is_ok())
+            },
+            ValueStore::Str(str) => {
+                let (current, new): (String, Arc<String>) = (current.into(), Arc::new(new.into()));
+                loop {
+                    let loaded = str.load();
+                    if **loaded != current {
+                        return Ok(false);
+                    }
+
+                    // Swapping compares the pointers, so if someone swapped in between we check the new content again
+                    let prev = str.compare_and_swap(&*loaded, new.clone());
+                    if Arc::ptr_eq(&*prev, &*loaded) {
+                        return Ok(true);
+                    }
+                }
+            },
+        }
+    }
+
+    /// Generic read-modify-write, retrying until the value didn't change in between, returning the previous value
+    pub fn fetch_update(&self, mut f: impl FnMut(Value) -> Result<Value, ()>) -> Result<Value, ()> {
+        loop {
+            let current = self.read();
+            let next = f(current.clone())?;
+            if self.compare_and_swap(current.clone(), next)? {
+                return Ok(current);
+            }
+        }
+    }
+
+    pub fn fetch_add(&self, value: Value) -> Result<Value, ()> {
+        match self {
+            ValueStore::Int(i) => Ok(Value::Int(i.fetch_add(value.try_into().ok().ok_or(())?, Ordering::Relaxed))),
+            ValueStore::Float(f) => {
+                let add: f64 = value.try_into().ok().ok_or(())?;
+                let prev = f.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |u| Some((f64::from_bits(u) + add).to_bits()))
+                    .expect("Update closure always returns Some");
+                Ok(Value::Float(f64::from_bits(prev)))
+            },
+            ValueStore::Bool(_) | ValueStore::Str(_) => Err(()),
+        }
+    }
+
+    pub fn toggle(&self) -> Result<bool, ()> {
+        if let ValueStore::Bool(b) = self {
+            Ok(b.fetch_xor(true, Ordering::Relaxed))
+        } else {
+            Err(())
+        }
+    }
+
+    pub fn append(&self, text: &str) -> Result<(), ()> {
+        if let ValueStore::Str(str) = self {
+            str.rcu(|cur| {
+                let mut next = String::with_capacity(cur.len() + text.len());
+                next.push_str(cur);
+                next.push_str(text);
+                next
+            });
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    pub fn read(&self) -> Value {
+        match self {
+            ValueStore::Int(i) => Value::Int(i.load(Ordering::Relaxed)),
+            ValueStore::Float(f) => {
+                let u = f.load(Ordering::Relaxed);
+                Value::Float(f64::from_be_bytes(u.to_be_bytes()))
+            },
+            ValueStore::Bool(b) => Value::Bool(b.load(Ordering::Relaxed)),
+            ValueStore::Str(str) => Value::Str(str.load().as_ref().clone()),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Deadlines for calls into plugins<br>
+//! A thread can't be stopped from the outside, so calls with a deadline run on a thread of their own and are abandoned once they are overdue.<br>
+//! The plugin is then marked unhealthy and not called anymore, the abandoned call keeps what it needs (like its library) alive until it returns.
+
+use std::{fmt::Display, sync::mpsc, thread, time::Duration};
+
+/// Why a call with a deadline did not return a result
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Missed {
+    /// Still running after the deadline
+    Overdue(Duration),
+    Panicked
+}
+
+impl Display for Missed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Missed::Overdue(deadline) => write!(f, "missed its deadline of {}ms", deadline.as_millis()),
+            Missed::Panicked => write!(f, "panicked")
+        }
+    }
+}
+
+/// Runs the call, on a thread of its own if there is a deadline<br>
+/// Without one it runs on this thread, and a panic is not caught
+pub fn call<R: Send + 'static>(deadline: Option<Duration>, f: impl FnOnce() -> R + Send + 'static) -> Result<R, Missed> {
+    let Some(deadline) = deadline else {
+        return Ok(f());
+    };
+
+    // Keeps the spans and stats of the call where they would be without a deadline
+    let span = tracing::Span::current();
+    let (sender, receiver) = mpsc::sync_channel(1);
+    thread::spawn(move || {
+        let _ = sender.send(span.in_scope(f));
+    });
+
+    match receiver.recv_timeout(deadline) {
+        Ok(result) => Ok(result),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(Missed::Overdue(deadline)),
+        // The sender was dropped without sending, the call unwound
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(Missed::Panicked)
+    }
+}
@@ -0,0 +1,260 @@
+//! Streams changes of the Datastore to websocket clients<br>
+//! <br>
+//! Clients send {"subscribe": {"keys": ["Finish", "Plugin*"], "max_rate": 5}} and get {"subscribed": id} back,<br>
+//! followed by the current values and then every change as {"subscription": id, "key": .., "value": .., "owner": .., "time": ..}.<br>
+//! Deleted keys are sent as {"subscription": id, "key": .., "deleted": true}, {"unsubscribe": {"id": id}} ends a subscription.<br>
+//! Frames are json text, or MessagePack binary when connecting with ?format=msgpack (commands can be sent in either).<br>
+//! Each subscription gets at most max_rate updates per second for every key, changes in between are dropped except for the latest.<br>
+//! A client that falls behind the changes gets the current values of its keys again, instead of every change it missed.
+
+use std::{collections::HashMap, io, net::{TcpListener, TcpStream}, sync::mpsc::TryRecvError, thread, time::{Duration, Instant}};
+
+use log::{info, warn};
+use plugin_sdk::{Datastore, KeyInfo, KeyPattern};
+use serde::Deserialize;
+use serde_json::json;
+use tungstenite::{handshake::server::{ErrorResponse, Request, Response}, http, error::ProtocolError, Error, Message};
+
+use crate::{config::WebsocketConfig, datastore::{Changes, Data}, http::value_to_json, journal::{JournalEntry, JournalEvent}};
+
+/// How long a read waits for a command before changes are sent out again
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Clients that don't finish the handshake in time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Changes kept for a connection until it reads them, more are dropped
+const CHANGES_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    MessagePack
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Command {
+    Subscribe { keys: Vec<String>, max_rate: Option<f64> },
+    Unsubscribe { id: u64 }
+}
+
+struct Subscription {
+    id: u64,
+    patterns: Vec<KeyPattern>,
+    interval: Duration,
+    keys: HashMap<String, KeyState>
+}
+
+#[derive(Default)]
+struct KeyState {
+    last_sent: Option<Instant>,
+    /// The latest change that was held back by the rate limit
+    pending: Option<serde_json::Value>
+}
+
+impl Subscription {
+    fn matches(&self, key: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.matches(key))
+    }
+
+    /// Sends the frame now if the rate limit allows it, otherwise keeps it until it does
+    fn offer(&mut self, key: &str, frame: serde_json::Value, out: &mut Vec<serde_json::Value>) {
+        let state = self.keys.entry(key.to_string()).or_default();
+        if state.last_sent.is_some_and(|last| last.elapsed() < self.interval) {
+            state.pending = Some(frame);
+        } else {
+            state.last_sent = Some(Instant::now());
+            state.pending = None;
+            out.push(frame);
+        }
+    }
+
+    /// Offers the current values of all matching keys, and deletes the keys that are gone
+    fn current(&mut self, data: &Data, out: &mut Vec<serde_json::Value>) {
+        let infos: Vec<KeyInfo> = data.list_keys(&KeyPattern::All).into_iter().filter(|info| self.matches(&info.key)).collect();
+        let id = self.id;
+        self.keys.retain(|key, _| {
+            let exists = infos.iter().any(|info| &info.key == key);
+            if !exists {
+                out.push(json!({ "subscription": id, "key": key, "deleted": true }));
+            }
+            exists
+        });
+
+        for info in infos {
+            if let Ok(value) = data.get_value(&info.handle) {
+                let frame = json!({ "subscription": id, "key": info.key, "value": value_to_json(&value), "owner": info.owner });
+                self.offer(&info.key, frame, out);
+            }
+        }
+    }
+
+    /// Held back frames whose key may be sent again
+    fn due(&mut self, out: &mut Vec<serde_json::Value>) {
+        for state in self.keys.values_mut() {
+            if state.pending.is_some() && state.last_sent.is_none_or(|last| last.elapsed() >= self.interval) {
+                state.last_sent = Some(Instant::now());
+                out.extend(state.pending.take());
+            }
+        }
+    }
+}
+
+/// Starts accepting websocket clients on the configured address
+pub fn spawn(config: &WebsocketConfig, data: &'static Data) -> io::Result<()> {
+    let listener = TcpListener::bind(&config.bind)?;
+    info!("Streaming changes on ws://{}", config.bind);
+
+    let max_rate = config.max_rate;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || {
+                        let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+                        if let Err(e) = serve(stream, data, max_rate) {
+                            warn!("Websocket connection {} failed: {}", peer, e);
+                        }
+                    });
+                },
+                Err(e) => warn!("Failed to accept websocket connection: {}", e)
+            }
+        }
+    });
+    Ok(())
+}
+
+// The handshake callback has to return the large ErrorResponse of tungstenite
+#[allow(clippy::result_large_err)]
+fn serve(stream: TcpStream, data: &'static Data, max_rate: f64) -> Result<(), String> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).map_err(|e| e.to_string())?;
+
+    let mut format = Format::Json;
+    let mut socket = tungstenite::accept_hdr(stream, |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+        let query = request.uri().query().unwrap_or_default();
+        match query.split('&').find_map(|pair| pair.strip_prefix("format=")) {
+            None | Some("json") => (),
+            Some("msgpack") => format = Format::MessagePack,
+            Some(other) => return Err(http::Response::builder().status(400).body(Some(format!("unknown format {}, expected json or msgpack", other))).expect("Valid response"))
+        }
+        Ok(response)
+    }).map_err(|e| e.to_string())?;
+    socket.get_mut().set_read_timeout(Some(POLL_INTERVAL)).map_err(|e| e.to_string())?;
+
+    // Only subscribed to the Datastore while the client has subscriptions
+    let mut changes: Option<Changes> = None;
+    let mut subscriptions: Vec<Subscription> = vec![];
+    let mut next_id = 1;
+
+    loop {
+        // The reply to a command goes out first, for subscribe followed by the current values
+        let mut out = match socket.read() {
+            Ok(Message::Text(text)) => command(serde_json::from_str(&text).map_err(|e| e.to_string()), data, max_rate, &mut subscriptions, &mut changes, &mut next_id),
+            Ok(Message::Binary(bytes)) => command(rmp_serde::from_slice(&bytes).map_err(|e| e.to_string()), data, max_rate, &mut subscriptions, &mut changes, &mut next_id),
+            Ok(_) => vec![],
+            Err(Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => vec![],
+            Err(Error::ConnectionClosed | Error::AlreadyClosed | Error::Protocol(ProtocolError::ResetWithoutClosingHandshake)) => return Ok(()),
+            Err(e) => return Err(e.to_string())
+        };
+
+        if let Some(changes) = &changes {
+            loop {
+                match changes.try_recv() {
+                    Ok(entry) => forward(entry, data, &mut subscriptions, &mut out),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(())
+                }
+            }
+            if changes.lagged() {
+                for subscription in &mut subscriptions {
+                    subscription.current(data, &mut out);
+                }
+            }
+        }
+        for subscription in &mut subscriptions {
+            subscription.due(&mut out);
+        }
+
+        for frame in out {
+            socket.write(encode(&frame, format)?).map_err(|e| e.to_string())?;
+        }
+        match socket.flush() {
+            Ok(()) => (),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(Error::ConnectionClosed | Error::AlreadyClosed) => return Ok(()),
+            Err(e) => return Err(e.to_string())
+        }
+    }
+}
+
+/// Runs a command of the client, returning the frames to answer with
+fn command(command: Result<Command, String>, data: &Data, max_rate: f64, subscriptions: &mut Vec<Subscription>, changes: &mut Option<Changes>, next_id: &mut u64) -> Vec<serde_json::Value> {
+    match command {
+        Ok(Command::Subscribe { keys, max_rate: rate }) => {
+            let rate = rate.unwrap_or(max_rate).min(max_rate);
+            if keys.is_empty() || rate.is_nan() || rate <= 0.0 {
+                return vec![json!({ "error": "subscribe needs at least one key and a max_rate greater than 0" })];
+            }
+
+            let mut subscription = Subscription {
+                id: *next_id,
+                patterns: keys.iter().map(|key| KeyPattern::Glob(key.clone())).collect(),
+                interval: Duration::from_secs_f64(1.0 / rate),
+                keys: HashMap::new()
+            };
+            *next_id += 1;
+
+            // Subscribing before the current values are read, so no change in between is missed
+            changes.get_or_insert_with(|| data.subscribe(CHANGES_CAPACITY));
+            let mut out = vec![json!({ "subscribed": subscription.id, "keys": keys, "max_rate": rate })];
+            subscription.current(data, &mut out);
+            subscriptions.push(subscription);
+            out
+        },
+        Ok(Command::Unsubscribe { id }) => {
+            let before = subscriptions.len();
+            subscriptions.retain(|s| s.id != id);
+            if subscriptions.len() == before {
+                return vec![json!({ "error": format!("there is no subscription {}", id) })];
+            }
+            if subscriptions.is_empty() {
+                *changes = None;
+            }
+            vec![json!({ "unsubscribed": id })]
+        },
+        Err(e) => vec![json!({ "error": format!("unknown command: {}", e) })]
+    }
+}
+
+/// Hands the change to every subscription of a key it touches
+fn forward(entry: JournalEntry, data: &Data, subscriptions: &mut [Subscription], out: &mut Vec<serde_json::Value>) {
+    let (gone, changed) = match entry.event {
+        JournalEvent::Create { key, value, .. } | JournalEvent::Set { key, value } => (None, Some((key, value))),
+        JournalEvent::Delete { key } => (Some(key), None),
+        JournalEvent::Rename { key, new_key } => {
+            // Subscribers of the old key see it deleted, those of the new key get its value
+            let value = data.get_data_handle(&new_key).and_then(|handle| data.get_value(&handle).ok());
+            (Some(key), value.map(|value| (new_key, value)))
+        },
+        JournalEvent::Register { .. } | JournalEvent::Deregister | JournalEvent::Initialized => return
+    };
+
+    for subscription in subscriptions {
+        let id = subscription.id;
+        if let Some(key) = gone.as_ref().filter(|key| subscription.matches(key)) {
+            // Deletions are never held back, and replace what was pending
+            subscription.keys.remove(key);
+            out.push(json!({ "subscription": id, "key": key, "deleted": true }));
+        }
+        if let Some((key, value)) = changed.as_ref().filter(|(key, _)| subscription.matches(key)) {
+            let frame = json!({ "subscription": id, "key": key, "value": value_to_json(value), "owner": entry.plugin, "time": entry.time });
+            subscription.offer(key, frame, out);
+        }
+    }
+}
+
+fn encode(frame: &serde_json::Value, format: Format) -> Result<Message, String> {
+    Ok(match format {
+        Format::Json => Message::Text(frame.to_string()),
+        Format::MessagePack => Message::Binary(rmp_serde::to_vec_named(frame).map_err(|e| e.to_string())?)
+    })
+}
@@ -1,42 +1,96 @@
-use std::sync::OnceLock;
+use std::sync::RwLock;
 
-use plugin_sdk::{Datastore, Plugin, Value, AccessToken, DataHandle};
+use plugin_sdk::{Datastore, Plugin, Value, AccessToken, DataHandle, Metadata, PluginConfig, LogRecord, LogLevel, Logger};
+use serde::Deserialize;
 
-static TOKEN: OnceLock<AccessToken> = OnceLock::new();
-static HANDLE: OnceLock<DataHandle> = OnceLock::new();
+// No OnceLocks, so init works again after end (the os does not always unload the library, keeping the statics)
+static TOKEN: RwLock<Option<AccessToken>> = RwLock::new(None);
+static HANDLE: RwLock<Option<DataHandle>> = RwLock::new(None);
+static CONFIG: RwLock<Config> = RwLock::new(Config { name: String::new(), greeting: String::new(), finish: 0.0 });
+
+/// Read from `[plugin.second_plugin]` in the loader config
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct Config {
+    /// Name we register under, only read on init
+    name: String,
+    /// Initial value of Plugin2, only read on init
+    greeting: String,
+    /// What update sets Finish to
+    finish: f64
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config { name: "Second Plugin".to_string(), greeting: "New Kid".to_string(), finish: 5.24 }
+    }
+}
+
+plugin_sdk::export_manifest!();
 
 #[no_mangle]
 pub fn init(storage: &'static dyn Datastore) -> Result<(), String> {
+    let config: Config = storage.get_plugin_config(env!("CARGO_CRATE_NAME")).get()?;
+
     // Other way of creating the plugin:
-    // We define the values here in place
-    let p = Plugin::new("Second Plugin".to_string(), "0.1.0".to_string(), None);
+    // We define the values here in place (or take them from the config)
+    let p = Plugin::new(config.name.clone(), "0.1.0".to_string(), None);
     if let Some(token) = storage.register_plugin(p) {
-        TOKEN.set(token).expect("Init was called twice!");
+        let mut slot = TOKEN.write().unwrap();
+        if slot.is_some() {
+            return Err("Init was called twice!".to_string());
+        }
+        *slot = Some(token);
 
 
     } else {
-        return Err("Second Plugin was unable to register".to_string());
+        return Err(format!("{} was unable to register", config.name));
     }
 
-    HANDLE.set(storage.create_value("Finish".to_string(), TOKEN.get().unwrap(), Value::Float(0.0)).unwrap()).expect("Init called twice");
+    let meta = Metadata { description: Some("Set once the second plugin ran its update".to_string()), min: Some(0.0), precision: Some(2), ..Default::default() };
+    let token = TOKEN.read().unwrap().clone().unwrap();
+    *HANDLE.write().unwrap() = Some(storage.create_value_with_metadata("Finish".to_string(), &token, Value::Float(0.0), meta).unwrap());
+
+    storage.create_value("Plugin2".to_string(), &token, Value::Str(config.greeting.clone())).unwrap();
 
-    storage.create_value("Plugin2".to_string(), TOKEN.get().unwrap(), Value::Str("New Kid".to_string())).unwrap();
+    // Persisted by the loader, so this counts up across restarts
+    let runs = storage.create_value_with_metadata("Runs".to_string(), &token, Value::Int(0), Metadata { persist: true, ..Default::default() }).unwrap();
+    storage.fetch_add(&runs, &token, Value::Int(1)).unwrap();
 
+    *CONFIG.write().unwrap() = config;
     Ok(())
 }
 
+#[no_mangle]
+pub fn config_changed(storage: &'static dyn Datastore, config: PluginConfig) {
+    let logger = Logger::new(storage, TOKEN.read().unwrap().clone().unwrap());
+    match config.get::<Config>() {
+        Ok(config) => {
+            logger.log(LogLevel::Info, "Finish changed", &[("finish", Value::Float(config.finish))]);
+            *CONFIG.write().unwrap() = config;
+        },
+        Err(e) => logger.warn(format!("Ignoring the new config: {}", e))
+    }
+}
+
 #[no_mangle]
 pub fn update(storage: &'static dyn Datastore) {
-    println!("I see plugin 1 was here: {}",storage.get_value(&storage.get_data_handle("Plugin1").unwrap()).unwrap().to_string());
+    let (handle, token) = (HANDLE.read().unwrap().clone().unwrap(), TOKEN.read().unwrap().clone().unwrap());
+    let logger = Logger::new(storage, token.clone());
+    logger.log(LogLevel::Info, "I see plugin 1 was here", &[("plugin1", storage.get_value(&storage.get_data_handle("Plugin1").unwrap()).unwrap())]);
 
 
     //storage.create_value("Finish".to_string(), Value::Str("Last laugh".to_string())).unwrap();
-    storage.set_value(&HANDLE.get().unwrap(), TOKEN.get().unwrap(),  Value::Float(5.24)).unwrap();
+    storage.set_value(&handle, &token,  Value::Float(CONFIG.read().unwrap().finish)).unwrap();
     storage.get_plugin(&"test_plugin".to_string()).unwrap().interact("".to_string(), "".to_string()).unwrap();
 }
 
 #[no_mangle]
 pub fn end(storage: &dyn Datastore) {
-    println!("{}, the downfall of western civilization", storage.deregister_plugin(TOKEN.get().unwrap()));
+    HANDLE.write().unwrap().take();
+    let token = TOKEN.write().unwrap().take().unwrap();
+    // Only handed a short lived storage here, so no Logger
+    storage.log(&token, LogRecord { level: LogLevel::Info, message: "The downfall of western civilization".to_string(), fields: vec![] });
+    storage.deregister_plugin(&token);
 
 }
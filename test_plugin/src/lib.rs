@@ -1,15 +1,24 @@
-use std::{sync::{Mutex, OnceLock}, thread::{JoinHandle, self}};
+use std::{sync::{Arc, Mutex, RwLock, atomic::{AtomicBool, Ordering}}, thread::{JoinHandle, self}};
 
-use plugin_sdk::{Datastore, Plugin, Value, AccessToken};
+use plugin_sdk::{Datastore, Plugin, Value, AccessToken, Logger, LogLevel};
 
 static RUNNER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+// Set when the loader shuts down, so the runner stops waiting for Finish
+static STOP: AtomicBool = AtomicBool::new(false);
 
-static STATE: OnceLock<State> = OnceLock::new();
+// Not a OnceLock, so init works again after end (the os does not always unload the library, keeping the statics)
+static STATE: RwLock<Option<Arc<State>>> = RwLock::new(None);
+
+fn state() -> Arc<State> {
+    STATE.read().unwrap().clone().expect("Plugin is not initialized")
+}
 
 mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
 
+plugin_sdk::export_manifest!();
+
 #[no_mangle]
 pub fn init(storage: &'static dyn Datastore) -> Result<(), String> {
     // This is one way of creating the plugin data:
@@ -18,10 +27,15 @@ pub fn init(storage: &'static dyn Datastore) -> Result<(), String> {
     let p = Plugin::new(built_info::PKG_NAME.to_string(), built_info::PKG_VERSION.to_string(), Some(run));
     
     if let Some(token) = storage.register_plugin(p) {
-        STATE.set(State {
+        let mut state = STATE.write().unwrap();
+        if state.is_some() {
+            return Err("Init should never be called twice".to_string());
+        }
+        *state = Some(Arc::new(State {
+            logger: Logger::new(storage, token.clone()),
             access_token: token,
             test_value: Mutex::new(3)
-        }).expect("Init should never be called twice");
+        }));
     } else {
         // It is important to error out if the plugin failed to initalize, as you would risk errors when the program ends
         // Also it is more professional for the plugin to return an error rather then panicing and taking the program with it
@@ -29,7 +43,7 @@ pub fn init(storage: &'static dyn Datastore) -> Result<(), String> {
     }
     
     
-    storage.create_value("Plugin1".to_string(), &STATE.get().unwrap().access_token, Value::Str("Fuck you world".to_string())).unwrap();
+    storage.create_value("Plugin1".to_string(), &state().access_token, Value::Str("Fuck you world".to_string())).unwrap();
     
 
     *RUNNER.lock().unwrap() = Some(thread::spawn(move | | { update(storage); }));
@@ -45,7 +59,10 @@ fn update(storage: &'static dyn Datastore) {
     let start = std::time::Instant::now();
     let mut res = None;
     while res.is_none() {
-        res = storage.get_data_handle(&"Finish");
+        if STOP.load(Ordering::Relaxed) {
+            return;
+        }
+        res = storage.get_data_handle("Finish");
         index += 1;
     }
 
@@ -53,23 +70,34 @@ fn update(storage: &'static dyn Datastore) {
 
     while let Value::Float(f) = storage.get_value(&res).unwrap() {
         index += 1;
-        if f != 0.0 {
+        if f != 0.0 || STOP.load(Ordering::Relaxed) {
             break;
         }
     }
-    println!("So Plugin 2 updated after {}ns and {} iter: {}",start.elapsed().as_nanos(), index, storage.get_value(&res).unwrap().to_string());
+    state().logger.log(LogLevel::Info, "So Plugin 2 updated", &[
+        ("after_ns", Value::Int(start.elapsed().as_nanos() as i64)),
+        ("iterations", Value::Int(index)),
+        ("finish", storage.get_value(&res).unwrap())
+    ]);
 
-    storage.create_value("Answer".to_string(), &STATE.get().unwrap().access_token, Value::Str("My final message... good bye...".to_string())).unwrap();
+    storage.create_value("Answer".to_string(), &state().access_token, Value::Str("My final message... good bye...".to_string())).unwrap();
 }
 
+#[allow(clippy::result_unit_err)]
 pub fn run(_methode: String, _args: String) -> Result<String, ()> {
     test();
     Ok("".to_string())
 }
 
 fn test() {
-    *STATE.get().unwrap().test_value.lock().unwrap() = 5;
-    println!("Around the world...");
+    *state().test_value.lock().unwrap() = 5;
+    state().logger.info("Around the world...");
+}
+
+// Optional, called before end when the loader shuts down
+#[no_mangle]
+pub fn shutdown(_storage: &'static dyn Datastore) {
+    STOP.store(true, Ordering::Relaxed);
 }
 
 #[no_mangle]
@@ -77,13 +105,17 @@ pub fn end(storage: &dyn Datastore) {
     if let Some(handle) = (*RUNNER.lock().unwrap()).take() {
         handle.join().expect("Thread failed... sad owo");
     }
+    STOP.store(false, Ordering::Relaxed);
     
     
-    println!("It is joe-over! {} {}", STATE.get().unwrap().test_value.lock().unwrap(), storage.deregister_plugin(&STATE.get().unwrap().access_token));
+    let state = STATE.write().unwrap().take().expect("Plugin is not initialized");
+    state.logger.log(LogLevel::Info, "It is joe-over!", &[("test_value", Value::Int(*state.test_value.lock().unwrap() as i64))]);
+    storage.deregister_plugin(&state.access_token);
 }
 
 #[derive(Debug)]
 struct State {
     access_token: AccessToken,
+    logger: Logger,
     test_value: Mutex<i32>
 }